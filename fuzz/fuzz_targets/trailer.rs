@@ -0,0 +1,25 @@
+//! 解析任意数据的尾部并提取全部附件，不应该panic、溢出或者分配过多内存
+//!
+//! 运行：`cargo fuzz run trailer fuzz/corpus/trailer`
+
+#![no_main]
+
+use hidden_files::{ExtractOptions, Extractor};
+use libfuzzer_sys::fuzz_target;
+use std::io::{self, Cursor};
+
+fuzz_target!(|data: &[u8]| {
+    // 不提供密码，避免恶意的密钥参数拖慢测试
+    let options = ExtractOptions {
+        password: None,
+        max_decompressed_size: 1024 * 1024,
+    };
+    if let Ok(Some(mut extractor)) = Extractor::new(Cursor::new(data), &options) {
+        let entries = extractor.attachments().entries.clone();
+        for entry in &entries {
+            let _ = extractor.extract(entry, io::sink());
+        }
+        let _ = extractor.verify();
+        let _ = extractor.carrier(io::sink());
+    }
+});
@@ -0,0 +1,493 @@
+//! 命令行工具，在没有图形界面的环境(CI、服务器)中使用
+
+use clap::{Parser, Subcommand, ValueEnum};
+use hidden_files::{
+    AppendSafety, Attachments, CancelToken, CarrierReport, Codec, EmbedOptions, EntryKind, Error,
+    ExtractOptions, NoProgress, Phase, ProgressEvent, VerifyStatus,
+};
+use indicatif::{ProgressBar, ProgressStyle};
+use serde_json::json;
+use std::{
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+/// 执行成功
+const EXIT_OK: u8 = 0;
+/// 执行失败
+const EXIT_ERROR: u8 = 1;
+/// 源文件中没有附件
+const EXIT_NO_ATTACHMENT: u8 = 3;
+/// 校验发现附件已损坏，或者附件信息已损坏
+const EXIT_CORRUPTED: u8 = 4;
+/// 没有提供密码或者密码错误
+const EXIT_PASSWORD: u8 = 5;
+
+#[derive(Parser)]
+#[command(
+    name = "hidden-files-cli",
+    version,
+    about = "把文件隐藏到图片等文件末尾"
+)]
+struct Cli {
+    /// 输出JSON格式的结果
+    #[arg(long, global = true)]
+    json: bool,
+
+    /// 附件加密使用的密码
+    #[arg(
+        long,
+        global = true,
+        env = "HIDDEN_FILES_PASSWORD",
+        hide_env_values = true
+    )]
+    password: Option<String>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// 把文件或文件夹保存到源文件中，源文件中已有附件时替换
+    Embed {
+        /// 源文件
+        carrier: PathBuf,
+        /// 附加文件或文件夹
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+        /// 保存的路径，不指定时直接修改源文件
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// 同时加密附件信息(文件名、大小等)，需要设置密码
+        #[arg(long)]
+        encrypt_metadata: bool,
+        /// 保存附件的修改时间和权限
+        #[arg(long)]
+        keep_metadata: bool,
+        /// 附件压缩算法
+        #[arg(long, value_enum, default_value_t = Compression::Zstd)]
+        compress: Compression,
+        /// 附件的保存方式
+        #[arg(long, value_enum, default_value_t = Layout::Append)]
+        layout: Layout,
+    },
+    /// 列出源文件中的附件
+    List {
+        /// 源文件
+        carrier: PathBuf,
+    },
+    /// 提取附件
+    Extract {
+        /// 源文件
+        carrier: PathBuf,
+        /// 只提取指定名称的附件
+        #[arg(long)]
+        entry: Option<String>,
+        /// 保存的路径，提取全部附件时为文件夹，默认为当前文件夹
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// 压缩的附件解压后允许的最大长度(字节)
+        #[arg(long)]
+        max_size: Option<u64>,
+    },
+    /// 移除附件，还原源文件
+    Strip {
+        /// 源文件
+        carrier: PathBuf,
+        /// 还原后保存的路径，不指定时直接截断源文件
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// 校验附件是否完整，不写入任何文件
+    Verify {
+        /// 源文件
+        carrier: PathBuf,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Compression {
+    None,
+    Deflate,
+    Zstd,
+}
+
+impl From<Compression> for Codec {
+    fn from(compression: Compression) -> Self {
+        match compression {
+            Compression::None => Codec::None,
+            Compression::Deflate => Codec::Deflate,
+            Compression::Zstd => Codec::Zstd,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Layout {
+    /// 追加在源文件末尾
+    Append,
+    /// 保存到PNG的私有块中，源文件必须是PNG
+    PngChunk,
+    /// 保存到JPEG的APP15段中，源文件必须是JPEG
+    JpegSegment,
+    /// 保存为PDF增量更新中的嵌入文件，源文件必须是PDF
+    PdfStream,
+    /// 保存到ZIP的中央目录之前，源文件必须是ZIP/JAR
+    ZipDirectory,
+}
+
+impl From<Layout> for hidden_files::Layout {
+    fn from(layout: Layout) -> Self {
+        match layout {
+            Layout::Append => hidden_files::Layout::Append,
+            Layout::PngChunk => hidden_files::Layout::PngChunk,
+            Layout::JpegSegment => hidden_files::Layout::JpegSegment,
+            Layout::PdfStream => hidden_files::Layout::PdfStream,
+            Layout::ZipDirectory => hidden_files::Layout::ZipDirectory,
+        }
+    }
+}
+
+/// 命令执行结果，`value`为JSON格式输出的内容
+struct Output {
+    code: u8,
+    message: String,
+    value: serde_json::Value,
+}
+
+impl Output {
+    fn ok(message: String, value: serde_json::Value) -> Self {
+        Self {
+            code: EXIT_OK,
+            message,
+            value,
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let output = run(&cli).unwrap_or_else(|err| Output {
+        code: exit_code(&err),
+        message: format!("错误: {}", err),
+        value: json!({ "status": error_status(&err), "message": err.to_string() }),
+    });
+    if cli.json {
+        println!("{}", output.value);
+    } else if output.code == EXIT_OK {
+        println!("{}", output.message);
+    } else {
+        eprintln!("{}", output.message);
+    }
+    ExitCode::from(output.code)
+}
+
+fn run(cli: &Cli) -> hidden_files::Result<Output> {
+    let password = cli.password.clone().filter(|password| !password.is_empty());
+    match &cli.command {
+        Command::Embed {
+            carrier,
+            files,
+            output,
+            encrypt_metadata,
+            keep_metadata,
+            compress,
+            layout,
+        } => {
+            // 密码是全局参数，可以在子命令之前指定，不能用clap的requires检查
+            if *encrypt_metadata && password.is_none() {
+                return Err(Error::PasswordRequired);
+            }
+            let options = EmbedOptions {
+                encrypt_metadata: *encrypt_metadata,
+                password,
+                keep_metadata: *keep_metadata,
+                compression: (*compress).into(),
+                layout: (*layout).into(),
+            };
+            // 按照这次的保存方式提示风险
+            let mut carrier_report = hidden_files::check_carrier(carrier)?;
+            carrier_report.layout = options.layout;
+            let warnings = carrier_report.warnings();
+            if !cli.json {
+                for warning in &warnings {
+                    eprintln!("警告: {}", warning);
+                }
+            }
+            let progress = progress_bar(cli.json);
+            hidden_files::embed(
+                carrier,
+                files,
+                output.as_deref(),
+                &options,
+                &report(&progress),
+                &not_cancled(),
+            )?;
+            progress.finish_and_clear();
+            let saved = output.as_deref().unwrap_or(carrier);
+            Ok(Output::ok(
+                format!("已保存: {}", saved.display()),
+                json!({ "status": "ok", "output": saved, "warnings": warnings }),
+            ))
+        }
+        Command::List { carrier } => {
+            let attachments = inspect(carrier, password.as_deref())?;
+            let report = hidden_files::check_carrier(carrier)?;
+            Ok(list(&attachments, &report))
+        }
+        Command::Extract {
+            carrier,
+            entry,
+            output,
+            max_size,
+        } => {
+            let attachments = inspect(carrier, password.as_deref())?;
+            let mut options = ExtractOptions {
+                password,
+                ..Default::default()
+            };
+            if let Some(max_size) = max_size {
+                options.max_decompressed_size = *max_size;
+            }
+            let progress = progress_bar(cli.json);
+            let output = match entry {
+                Some(name) => {
+                    let entry = attachments
+                        .entries
+                        .iter()
+                        .find(|entry| &entry.name == name && entry.kind == EntryKind::File)
+                        .ok_or_else(|| Error::InvalidInput(format!("没有找到附件:{}", name)))?;
+                    // 默认保存到当前文件夹，只使用附件的文件名
+                    let output = output
+                        .clone()
+                        .unwrap_or_else(|| PathBuf::from(name.rsplit('/').next().unwrap_or(name)));
+                    hidden_files::extract(
+                        carrier,
+                        &attachments,
+                        entry,
+                        &output,
+                        &options,
+                        &report(&progress),
+                        &not_cancled(),
+                    )?;
+                    output
+                }
+                None => {
+                    let output = output.clone().unwrap_or_else(|| PathBuf::from("."));
+                    hidden_files::extract_all(
+                        carrier,
+                        &attachments,
+                        &output,
+                        &options,
+                        &report(&progress),
+                        &not_cancled(),
+                    )?;
+                    output
+                }
+            };
+            progress.finish_and_clear();
+            Ok(Output::ok(
+                format!("已提取到: {}", output.display()),
+                json!({ "status": "ok", "output": output }),
+            ))
+        }
+        Command::Strip { carrier, output } => {
+            let attachments = inspect(carrier, password.as_deref())?;
+            let progress = progress_bar(cli.json);
+            hidden_files::strip(
+                carrier,
+                &attachments,
+                output.as_deref(),
+                &report(&progress),
+                &not_cancled(),
+            )?;
+            progress.finish_and_clear();
+            let saved = output.as_deref().unwrap_or(carrier);
+            Ok(Output::ok(
+                format!("附件已移除: {}", saved.display()),
+                json!({ "status": "ok", "output": saved }),
+            ))
+        }
+        Command::Verify { carrier } => {
+            let attachments = inspect(carrier, password.as_deref())?;
+            let progress = progress_bar(cli.json);
+            let results =
+                hidden_files::verify(carrier, &attachments, &report(&progress), &not_cancled())?;
+            progress.finish_and_clear();
+            Ok(verify_output(&results))
+        }
+    }
+}
+
+/// 读取附件信息，没有附件或者附件信息加密时返回错误
+fn inspect(carrier: &Path, password: Option<&str>) -> hidden_files::Result<Attachments> {
+    match hidden_files::inspect(carrier, password, &NoProgress, &not_cancled())? {
+        Some(attachments) if attachments.sealed => Err(Error::PasswordRequired),
+        Some(attachments) => Ok(attachments),
+        None => Err(Error::NoAttachment),
+    }
+}
+
+/// 错误对应的退出码
+fn exit_code(err: &Error) -> u8 {
+    match err {
+        Error::NoAttachment => EXIT_NO_ATTACHMENT,
+        Error::Corrupted(_) | Error::CorruptTrailer(_) | Error::Decode(_) => EXIT_CORRUPTED,
+        Error::WrongPassword | Error::PasswordRequired => EXIT_PASSWORD,
+        _ => EXIT_ERROR,
+    }
+}
+
+/// JSON输出中的错误状态
+fn error_status(err: &Error) -> &'static str {
+    match err {
+        Error::NoAttachment => "no_attachment",
+        Error::Corrupted(_) | Error::CorruptTrailer(_) | Error::Decode(_) => "corrupted",
+        Error::WrongPassword | Error::PasswordRequired => "password",
+        Error::Cancelled => "cancelled",
+        _ => "error",
+    }
+}
+
+fn list(attachments: &Attachments, report: &CarrierReport) -> Output {
+    let mut warnings = report.warnings();
+    warnings.extend(attachments.warnings.iter().cloned());
+    let mut lines = vec![format!(
+        "源文件格式: {}",
+        report.format.map(|format| format.name).unwrap_or("未知")
+    )];
+    for warning in &warnings {
+        lines.push(format!("警告: {}", warning));
+    }
+    for entry in &attachments.entries {
+        lines.push(match entry.kind {
+            EntryKind::File => format!(
+                "{:>12}  {}",
+                hidden_files::get_size_str(entry.size),
+                entry.name
+            ),
+            EntryKind::Dir => format!("{:>12}  {}/", "-", entry.name),
+        });
+    }
+    let total: u64 = attachments.entries.iter().map(|entry| entry.size).sum();
+    lines.push(format!(
+        "共{}个附件 大小:{}{}",
+        attachments.entries.len(),
+        hidden_files::get_size_str(total),
+        if attachments.is_encrypted() {
+            " 已加密"
+        } else {
+            ""
+        }
+    ));
+
+    let entries: Vec<_> = attachments
+        .entries
+        .iter()
+        .map(|entry| {
+            json!({
+                "name": entry.name,
+                "kind": match entry.kind {
+                    EntryKind::File => "file",
+                    EntryKind::Dir => "dir",
+                },
+                "size": entry.size,
+                "stored_len": entry.stored_len,
+                "compression": format!("{:?}", entry.codec).to_lowercase(),
+                "encrypted": entry.nonce.is_some(),
+                "mode": entry.mode,
+                "mtime": entry.mtime,
+            })
+        })
+        .collect();
+    Output::ok(
+        lines.join("\n"),
+        json!({
+            "status": "ok",
+            "carrier_len": attachments.carrier_len(),
+            "encrypted": attachments.is_encrypted(),
+            "layout": match attachments.layout {
+                hidden_files::Layout::Append => "append",
+                hidden_files::Layout::PngChunk => "png_chunk",
+                hidden_files::Layout::JpegSegment => "jpeg_segment",
+                hidden_files::Layout::PdfStream => "pdf_stream",
+                hidden_files::Layout::ZipDirectory => "zip_directory",
+            },
+            "format": report.format.map(|format| format.name),
+            "append_safety": match report.safety() {
+                AppendSafety::Safe => "safe",
+                AppendSafety::Caution => "caution",
+                AppendSafety::Risky => "risky",
+                AppendSafety::Unsupported => "unsupported",
+            },
+            "trailing_len": report.trailing_len(),
+            "entries": entries,
+            "warnings": warnings,
+        }),
+    )
+}
+
+fn verify_output(results: &[(String, VerifyStatus)]) -> Output {
+    let corrupted = results
+        .iter()
+        .any(|(_, status)| *status == VerifyStatus::Corrupted);
+    let status_str = |status: &VerifyStatus| match status {
+        VerifyStatus::Ok => "ok",
+        VerifyStatus::Unchecked => "unchecked",
+        VerifyStatus::Corrupted => "corrupted",
+    };
+    let message = results
+        .iter()
+        .map(|(name, status)| format!("{}: {}", name, status_str(status)))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let entries: Vec<_> = results
+        .iter()
+        .map(|(name, status)| json!({ "name": name, "status": status_str(status) }))
+        .collect();
+    Output {
+        code: if corrupted { EXIT_CORRUPTED } else { EXIT_OK },
+        message,
+        value: json!({
+            "status": if corrupted { "corrupted" } else { "ok" },
+            "entries": entries,
+        }),
+    }
+}
+
+/// 终端进度条，输出JSON或者不在终端中运行时不显示
+fn progress_bar(json: bool) -> ProgressBar {
+    if json {
+        return ProgressBar::hidden();
+    }
+    let progress = ProgressBar::new(0);
+    progress.set_style(
+        ProgressStyle::with_template(
+            "{msg} {bar:40.cyan/blue} {bytes}/{total_bytes} {binary_bytes_per_sec} {elapsed_precise}",
+        )
+        .unwrap_or_else(|_| ProgressStyle::default_bar()),
+    );
+    progress
+}
+
+/// 把进度事件显示到进度条
+fn report(progress: &ProgressBar) -> impl Fn(&ProgressEvent) + '_ {
+    move |event| {
+        progress.set_message(match event.phase {
+            Phase::Inspect => "读取",
+            Phase::Carrier => "复制",
+            Phase::Embed => "保存",
+            Phase::Extract => "提取",
+            Phase::Verify => "校验",
+            Phase::Strip => "还原",
+        });
+        progress.set_length(event.bytes_total);
+        progress.set_position(event.bytes_done);
+    }
+}
+
+/// 命令行中不支持取消操作
+fn not_cancled() -> CancelToken {
+    CancelToken::new()
+}
@@ -0,0 +1,72 @@
+use bincode::{Decode, Encode};
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use std::io::{self, Read, Write};
+
+/// zstd压缩级别
+const ZSTD_LEVEL: i32 = 3;
+
+/// 附件使用的压缩算法
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Encode, Decode)]
+pub enum Codec {
+    /// 不压缩
+    #[default]
+    None,
+    Deflate,
+    Zstd,
+}
+
+/// 压缩写入，最后必须调用`finish`
+pub(crate) enum CompressWriter<W: Write> {
+    None(W),
+    Deflate(DeflateEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+}
+
+impl<W: Write> CompressWriter<W> {
+    pub fn new(codec: Codec, inner: W) -> io::Result<Self> {
+        Ok(match codec {
+            Codec::None => Self::None(inner),
+            Codec::Deflate => Self::Deflate(DeflateEncoder::new(inner, Compression::default())),
+            Codec::Zstd => Self::Zstd(zstd::Encoder::new(inner, ZSTD_LEVEL)?),
+        })
+    }
+
+    /// 写入剩余的压缩数据，返回内部的writer
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Self::None(inner) => Ok(inner),
+            Self::Deflate(encoder) => encoder.finish(),
+            Self::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for CompressWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::None(inner) => inner.write(data),
+            Self::Deflate(encoder) => encoder.write(data),
+            Self::Zstd(encoder) => encoder.write(data),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::None(inner) => inner.flush(),
+            Self::Deflate(encoder) => encoder.flush(),
+            Self::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// 解压读取
+pub(crate) fn decompress_reader<'a, R: Read + 'a>(
+    codec: Codec,
+    inner: R,
+) -> io::Result<Box<dyn Read + 'a>> {
+    Ok(match codec {
+        Codec::None => Box::new(inner),
+        Codec::Deflate => Box::new(DeflateDecoder::new(inner)),
+        Codec::Zstd => Box::new(zstd::Decoder::new(inner)?),
+    })
+}
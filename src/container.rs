@@ -0,0 +1,497 @@
+use bincode::{config, Decode, Encode};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use crate::{
+    codec::Codec,
+    crypto::{self, KdfParams, HASH_LEN, NONCE_LEN, SEAL_NONCE_LEN},
+    error::{Error, Result},
+    layout::Layout,
+    progress::ChunkProgress,
+    utils::FileSpec,
+};
+
+// 旧格式(v1)文件结构：源文件字节 附加文件字节 RUSTAPPEND666E FileSpec RUSTAPPEND666S
+const START_BYTES: &str = "RUSTAPPEND666S";
+const END_BYTES: &str = "RUSTAPPEND666E";
+/// v1格式的FileSpec最大长度，超过认为没有附件
+const LEGACY_MAX_SPEC_LEN: usize = 4096;
+
+// v2格式文件结构：源文件字节 附件1字节 附件2字节 ... 头部(Manifest或SealedHeader) 尾部(Footer)
+const FOOTER_MAGIC: &str = "RUSTAPPEND666F";
+pub(crate) const FORMAT_VERSION: u16 = 2;
+/// 尾部固定长度：版本(2) 标志(2) 头部长度(4) 头部校验和(4) 魔数(14)
+pub(crate) const FOOTER_LEN: usize = 2 + 2 + 4 + 4 + FOOTER_MAGIC.len();
+/// 头部是加密后的SealedHeader
+pub(crate) const FLAG_SEALED: u16 = 0x1;
+/// 当前版本支持的标志位
+const KNOWN_FLAGS: u16 = FLAG_SEALED;
+/// 头部最大长度
+const MAX_HEADER_LEN: u32 = 1024 * 1024;
+
+/// 附件类型
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Encode, Decode)]
+pub enum EntryKind {
+    File,
+    /// 文件夹没有数据，用来保留空文件夹和权限
+    Dir,
+}
+
+/// 附件清单中的一项，只保存文件名，不保存源文件所在的路径
+#[derive(Clone, Debug, Encode, Decode)]
+pub struct Entry {
+    /// 附件名称，文件夹中的附件为使用`/`分隔的相对路径
+    pub name: String,
+    /// 附件原始长度
+    pub size: u64,
+    /// 附件数据相对于附件区开始位置的偏移
+    pub offset: u64,
+    pub kind: EntryKind,
+    /// 文件权限(unix)，保存附件时选择保留文件属性才有
+    pub mode: Option<u32>,
+    /// 修改时间(UNIX时间戳，秒)，保存附件时选择保留文件属性才有
+    pub mtime: Option<u64>,
+    /// 附件数据在源文件中的长度，加密后比原始长度长
+    pub stored_len: u64,
+    /// 加密使用的nonce，没有加密时为None
+    pub nonce: Option<[u8; NONCE_LEN]>,
+    /// 压缩算法，先压缩再加密
+    pub codec: Codec,
+    /// 附件在源文件中的数据(压缩、加密之后)的SHA-256，旧格式没有
+    pub sha256: Option<[u8; HASH_LEN]>,
+}
+
+/// 附件清单，即v2格式的头部
+#[derive(Clone, Debug, Default, Encode, Decode)]
+pub struct Manifest {
+    /// 附件区总长度
+    pub data_len: u64,
+    pub entries: Vec<Entry>,
+    /// 附件加密时的密钥参数
+    pub encryption: Option<KdfParams>,
+    /// 原始源文件的SHA-256，移除附件时用来校验还原的文件
+    pub carrier_sha256: Option<[u8; HASH_LEN]>,
+}
+
+/// 加密后的附件清单，除了密钥参数外不暴露任何附件信息
+#[derive(Clone, Debug, Encode, Decode)]
+struct SealedHeader {
+    kdf: KdfParams,
+    nonce: [u8; SEAL_NONCE_LEN],
+    ciphertext: Vec<u8>,
+}
+
+/// 从源文件中读取到的附件信息
+#[derive(Clone, Debug)]
+pub struct Attachments {
+    /// 附件区开始位置，即原始源文件的长度。
+    /// 不是追加在末尾时为拼接后的数据中的位置，不是源文件中的位置，所以不公开
+    pub(crate) data_start: u64,
+    pub entries: Vec<Entry>,
+    pub encryption: Option<KdfParams>,
+    /// 原始源文件的SHA-256，旧格式没有
+    pub carrier_sha256: Option<[u8; HASH_LEN]>,
+    /// 附件信息已加密但是没有提供密码，此时`entries`为空，`carrier_len`也不准确
+    pub sealed: bool,
+    /// 检查附件时发现的问题，如旧格式中保存了完整路径
+    pub warnings: Vec<String>,
+    /// 附件在源文件中的保存方式
+    pub layout: Layout,
+}
+
+impl Attachments {
+    /// 移除附件后还原的原始源文件长度
+    pub fn carrier_len(&self) -> u64 {
+        self.data_start
+    }
+
+    /// 附件在拼接后的数据中的开始位置和结束位置，只有追加在末尾时与源文件中的位置相同
+    pub(crate) fn range(&self, entry: &Entry) -> (u64, u64) {
+        let start = self.data_start + entry.offset;
+        (start, start + entry.stored_len)
+    }
+
+    /// 附件是否加密
+    pub fn is_encrypted(&self) -> bool {
+        self.encryption.is_some()
+    }
+}
+
+/// 文件末尾的固定长度尾部，用于一次定位头部
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Footer {
+    pub version: u16,
+    pub flags: u16,
+    pub header_len: u32,
+    pub header_crc: u32,
+}
+
+impl Footer {
+    pub fn new(header: &[u8], flags: u16) -> Result<Self> {
+        let header_len = u32::try_from(header.len())
+            .ok()
+            .filter(|len| *len <= MAX_HEADER_LEN)
+            .ok_or_else(|| Error::InvalidInput("头部数据太长！".to_string()))?;
+        Ok(Self {
+            version: FORMAT_VERSION,
+            flags,
+            header_len,
+            header_crc: crc32fast::hash(header),
+        })
+    }
+
+    pub fn to_bytes(self) -> [u8; FOOTER_LEN] {
+        let mut buf = [0; FOOTER_LEN];
+        buf[0..2].copy_from_slice(&self.version.to_le_bytes());
+        buf[2..4].copy_from_slice(&self.flags.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.header_len.to_le_bytes());
+        buf[8..12].copy_from_slice(&self.header_crc.to_le_bytes());
+        buf[12..].copy_from_slice(FOOTER_MAGIC.as_bytes());
+        buf
+    }
+
+    /// 魔数不匹配时返回None
+    pub fn from_bytes(buf: &[u8; FOOTER_LEN]) -> Option<Self> {
+        if &buf[12..] != FOOTER_MAGIC.as_bytes() {
+            return None;
+        }
+        Some(Self {
+            version: u16::from_le_bytes([buf[0], buf[1]]),
+            flags: u16::from_le_bytes([buf[2], buf[3]]),
+            header_len: u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]),
+            header_crc: u32::from_le_bytes([buf[8], buf[9], buf[10], buf[11]]),
+        })
+    }
+}
+
+/// 在附件字节之后写入头部和尾部
+///
+/// `seal_key`不为None时加密整个附件清单，密钥参数使用`manifest.encryption`
+pub(crate) fn write_trailer<W: Write>(
+    output: &mut W,
+    manifest: &Manifest,
+    seal_key: Option<&chacha20poly1305::Key>,
+) -> Result<()> {
+    let mut header = bincode::encode_to_vec(manifest, config::standard())?;
+    let mut flags = 0;
+    if let Some(key) = seal_key {
+        let kdf = manifest.encryption.clone().ok_or(Error::PasswordRequired)?;
+        let (nonce, ciphertext) = crypto::seal(key, &header)?;
+        let sealed = SealedHeader {
+            kdf,
+            nonce,
+            ciphertext,
+        };
+        header = bincode::encode_to_vec(&sealed, config::standard())?;
+        flags |= FLAG_SEALED;
+    }
+    let footer = Footer::new(&header, flags)?;
+    output.write_all(&header)?;
+    output.write_all(&footer.to_bytes())?;
+    Ok(())
+}
+
+fn corrupt(msg: impl Into<String>) -> Error {
+    Error::CorruptTrailer(msg.into())
+}
+
+/// 解码头部数据，限制解码时分配的内存，并且要求正好用完全部数据。
+/// 头部来自不可信的文件，解码失败也作为尾部损坏处理
+fn decode_header<T: Decode>(bytes: &[u8]) -> Result<T> {
+    let config = config::standard().with_limit::<{ MAX_HEADER_LEN as usize }>();
+    let (value, len) = bincode::decode_from_slice(bytes, config)
+        .map_err(|err| corrupt(format!("附件信息解析失败:{}", err)))?;
+    if len != bytes.len() {
+        return Err(corrupt("头部数据长度不一致！"));
+    }
+    Ok(value)
+}
+
+/// 检查附件信息是否与附件区一致，不信任文件中记录的任何长度
+fn check_manifest(manifest: &Manifest) -> Result<()> {
+    for entry in &manifest.entries {
+        let in_range = entry
+            .offset
+            .checked_add(entry.stored_len)
+            .is_some_and(|end| end <= manifest.data_len);
+        // 没有压缩也没有加密时，保存的长度就是原始长度
+        let plain = entry.codec == Codec::None && entry.nonce.is_none();
+        let valid = in_range
+            && !entry.name.is_empty()
+            && (!plain || entry.size == entry.stored_len)
+            && (entry.nonce.is_none() || manifest.encryption.is_some())
+            && (entry.kind == EntryKind::File || entry.stored_len == 0);
+        if !valid {
+            return Err(corrupt(format!("附件位置错误:{}", entry.name)));
+        }
+    }
+    Ok(())
+}
+
+/// 从指定位置读满缓冲区
+fn read_exact_at<R: Read + Seek>(reader: &mut R, buf: &mut [u8], offset: u64) -> Result<()> {
+    reader.seek(SeekFrom::Start(offset))?;
+    reader.read_exact(buf)?;
+    Ok(())
+}
+
+/// 读取文件末尾的附件信息，同时兼容v1格式
+///
+/// 没有附件时返回None，附件信息加密时需要提供密码。派生密钥前后检查是否取消
+pub(crate) fn read_trailer<R: Read + Seek>(
+    file: &mut R,
+    password: Option<&str>,
+    chunks: &mut ChunkProgress,
+) -> Result<Option<Attachments>> {
+    let file_size = file.seek(SeekFrom::End(0))?;
+    if file_size >= FOOTER_LEN as u64 {
+        let mut buf = [0; FOOTER_LEN];
+        let footer_start = file_size - FOOTER_LEN as u64;
+        read_exact_at(file, &mut buf, footer_start)?;
+        if let Some(footer) = Footer::from_bytes(&buf) {
+            return read_v2(file, footer_start, footer, password, chunks).map(Some);
+        }
+    }
+    read_v1(file, file_size)
+}
+
+fn read_v2<R: Read + Seek>(
+    file: &mut R,
+    footer_start: u64,
+    footer: Footer,
+    password: Option<&str>,
+    chunks: &mut ChunkProgress,
+) -> Result<Attachments> {
+    if footer.version != FORMAT_VERSION {
+        return Err(corrupt(format!("不支持的格式版本:{}", footer.version)));
+    }
+    if footer.flags & !KNOWN_FLAGS != 0 {
+        return Err(corrupt(format!("不支持的格式标志:{:#x}", footer.flags)));
+    }
+    if footer.header_len > MAX_HEADER_LEN || footer.header_len as u64 > footer_start {
+        return Err(corrupt("头部长度错误！"));
+    }
+    let header_start = footer_start - footer.header_len as u64;
+    let mut header = vec![0; footer.header_len as usize];
+    read_exact_at(file, &mut header, header_start)?;
+    if crc32fast::hash(&header) != footer.header_crc {
+        return Err(corrupt("头部校验失败！"));
+    }
+    if footer.flags & FLAG_SEALED != 0 {
+        let sealed: SealedHeader = decode_header(&header)?;
+        sealed.kdf.check()?;
+        let password = match password {
+            Some(password) => password,
+            None => {
+                return Ok(Attachments {
+                    data_start: header_start,
+                    entries: vec![],
+                    encryption: Some(sealed.kdf),
+                    carrier_sha256: None,
+                    sealed: true,
+                    warnings: vec![],
+                    layout: Layout::Append,
+                })
+            }
+        };
+        chunks.check()?;
+        let key = sealed.kdf.derive_key(password)?;
+        chunks.check()?;
+        header = crypto::open(&key, &sealed.nonce, &sealed.ciphertext)?;
+    }
+    let manifest: Manifest = decode_header(&header)?;
+    if let Some(kdf) = &manifest.encryption {
+        kdf.check()?;
+    }
+    if manifest.data_len > header_start {
+        return Err(corrupt("附件长度错误！"));
+    }
+    check_manifest(&manifest)?;
+    Ok(Attachments {
+        data_start: header_start - manifest.data_len,
+        entries: manifest.entries,
+        encryption: manifest.encryption,
+        carrier_sha256: manifest.carrier_sha256,
+        sealed: false,
+        warnings: vec![],
+        layout: Layout::Append,
+    })
+}
+
+/// 旧格式：一次读取文件末尾的数据，在内存中查找结束字节
+fn read_v1<R: Read + Seek>(file: &mut R, file_size: u64) -> Result<Option<Attachments>> {
+    let start_bytes = START_BYTES.as_bytes();
+    let end_bytes = END_BYTES.as_bytes();
+    if file_size < (start_bytes.len() + end_bytes.len()) as u64 {
+        return Ok(None);
+    }
+    let tail_len =
+        file_size.min((LEGACY_MAX_SPEC_LEN + start_bytes.len() + end_bytes.len()) as u64);
+    let tail_start = file_size - tail_len;
+    let mut tail = vec![0; tail_len as usize];
+    read_exact_at(file, &mut tail, tail_start)?;
+
+    let spec_end = tail.len() - start_bytes.len();
+    if &tail[spec_end..] != start_bytes {
+        return Ok(None);
+    }
+    let end_pos = match tail[..spec_end]
+        .windows(end_bytes.len())
+        .rposition(|w| w == end_bytes)
+    {
+        Some(pos) => pos,
+        None => {
+            log::debug!("数据太长，提前结束！");
+            return Ok(None);
+        }
+    };
+    let f: FileSpec = decode_header(&tail[end_pos + end_bytes.len()..spec_end])?;
+
+    let end_offset = tail_start + end_pos as u64;
+    if f.size > end_offset || f.name.is_empty() {
+        return Err(corrupt("附件长度错误！"));
+    }
+    // 旧格式保存了附件在发送者电脑上的完整路径
+    let mut warnings = vec![];
+    if !f.path.is_empty() {
+        warnings.push(format!("旧格式附件中包含原始路径:{}", f.path));
+    }
+    Ok(Some(Attachments {
+        data_start: end_offset - f.size,
+        entries: vec![Entry {
+            name: f.name,
+            size: f.size,
+            offset: 0,
+            stored_len: f.size,
+            kind: EntryKind::File,
+            mode: None,
+            mtime: None,
+            nonce: None,
+            codec: Codec::None,
+            sha256: None,
+        }],
+        encryption: None,
+        carrier_sha256: None,
+        sealed: false,
+        warnings,
+        layout: Layout::Append,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Cursor, path::Path};
+
+    use super::*;
+    use crate::{
+        layout,
+        progress::{no_progress, Phase},
+        ExtractOptions, Extractor,
+    };
+
+    /// 读取附件信息的结果：没有附件、有附件、附件信息已加密、附件信息损坏
+    fn outcome(data: &[u8]) -> Result<&'static str> {
+        let res = no_progress(Phase::Inspect, 0, |chunks| {
+            layout::read_attachments(Cursor::new(data), None, chunks)
+        });
+        match res {
+            Ok((_, None)) => Ok("none"),
+            Ok((_, Some(attachments))) if attachments.sealed => Ok("sealed"),
+            Ok((_, Some(_))) => Ok("some"),
+            Err(Error::CorruptTrailer(_)) => Ok("corrupt"),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// 旧格式：源文件 附件 结束字节 FileSpec 开始字节
+    fn legacy_file(carrier: &[u8], name: &str, data: &[u8]) -> Vec<u8> {
+        let spec = FileSpec {
+            path: format!("C:\\Users\\test\\{}", name),
+            name: name.to_string(),
+            size: data.len() as u64,
+            ..Default::default()
+        };
+        let mut file = carrier.to_vec();
+        file.extend_from_slice(data);
+        file.extend_from_slice(END_BYTES.as_bytes());
+        file.extend(bincode::encode_to_vec(spec, config::standard()).unwrap());
+        file.extend_from_slice(START_BYTES.as_bytes());
+        file
+    }
+
+    #[test]
+    fn read_legacy() {
+        let carrier = vec![0x5a; 3000];
+        let file = legacy_file(&carrier, "old.txt", b"legacy attachment");
+        let attachments = no_progress(Phase::Inspect, 0, |chunks| {
+            read_trailer(&mut Cursor::new(&file), None, chunks)
+        })
+        .unwrap()
+        .unwrap();
+        assert_eq!(attachments.carrier_len(), carrier.len() as u64);
+        assert_eq!(attachments.entries.len(), 1);
+        assert_eq!(attachments.entries[0].name, "old.txt");
+        // 旧格式中的完整路径只提示，不使用
+        assert_eq!(attachments.warnings.len(), 1);
+
+        let options = ExtractOptions::default();
+        let mut extractor = Extractor::new(Cursor::new(&file), &options)
+            .unwrap()
+            .unwrap();
+        let entry = extractor.attachments().entries[0].clone();
+        assert_eq!(extractor.read(&entry).unwrap(), b"legacy attachment");
+        let mut original = vec![];
+        extractor.carrier(&mut original).unwrap();
+        assert_eq!(original, carrier);
+    }
+
+    #[test]
+    fn legacy_size_too_large() {
+        let mut file = legacy_file(b"", "old.txt", b"data");
+        // 把FileSpec中的大小改为超过文件长度
+        let spec = FileSpec {
+            name: "old.txt".to_string(),
+            size: 1 << 40,
+            ..Default::default()
+        };
+        file.truncate(4 + END_BYTES.len());
+        file.extend(bincode::encode_to_vec(spec, config::standard()).unwrap());
+        file.extend_from_slice(START_BYTES.as_bytes());
+        assert!(matches!(outcome(&file), Ok("corrupt")));
+    }
+
+    #[test]
+    fn fuzz_corpus() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz/corpus/trailer");
+        let mut count = 0;
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let name = path.file_name().unwrap().to_string_lossy().to_string();
+            let outcome = match outcome(&fs::read(&path).unwrap()) {
+                Ok(outcome) => outcome,
+                Err(err) => panic!("{}: 意外的错误 {:?}", name, err),
+            };
+            let expected = match name.as_str() {
+                "empty" | "short" | "pdf-bad-prev" => "none",
+                "v1" | "v2-plain" | "v2-deflate" | "v2-zstd" | "v2-dir" | "v2-encrypted"
+                | "png-chunk" | "jpeg-segment" | "pdf-stream" | "zip-directory" => "some",
+                "png-chunk-sealed" | "v2-sealed" => "sealed",
+                "v1-forged-size"
+                | "v1-garbage-spec"
+                | "v1-marker-only"
+                | "v2-bad-crc"
+                | "v2-bad-flags"
+                | "v2-bad-version"
+                | "v2-footer-only"
+                | "v2-forged-data-len"
+                | "v2-forged-entry-count"
+                | "v2-header-len-huge"
+                | "v2-truncated-header" => "corrupt",
+                // 模糊测试新增的输入只要求返回上面的某个结果
+                _ => outcome,
+            };
+            assert_eq!(outcome, expected, "{}", name);
+            count += 1;
+        }
+        assert!(count >= 25);
+    }
+}
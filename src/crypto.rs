@@ -0,0 +1,390 @@
+use argon2::{Algorithm, Argon2, Params, Version};
+use bincode::{Decode, Encode};
+use chacha20poly1305::{
+    aead::{
+        rand_core::RngCore,
+        stream::{DecryptorBE32, EncryptorBE32},
+        Aead, OsRng,
+    },
+    Key, KeyInit, XChaCha20Poly1305, XNonce,
+};
+use sha2::{Digest, Sha256};
+use std::io::{self, Read, Write};
+
+use crate::error::{DecryptError, Error, Result};
+
+/// 每一块明文的长度，每块密文会多出16字节的认证标签
+const CHUNK_SIZE: usize = 64 * 1024;
+const TAG_LEN: usize = 16;
+/// XChaCha20的nonce长度减去STREAM构造使用的5字节计数器
+pub(crate) const NONCE_LEN: usize = 19;
+/// 一次性加密使用完整的XChaCha20 nonce
+pub(crate) const SEAL_NONCE_LEN: usize = 24;
+const SALT_LEN: usize = 16;
+/// SHA-256摘要长度
+pub(crate) const HASH_LEN: usize = 32;
+/// 解密时允许的最大内存开销(KiB)，防止恶意文件耗尽内存。
+/// 密钥参数在头部中没有经过认证，上限只比`KdfParams::generate`使用的参数略高
+const MAX_M_COST: u32 = 256 * 1024;
+/// 解密时允许的最大迭代次数和并行度，防止恶意文件长时间占用CPU
+const MAX_T_COST: u32 = 10;
+const MAX_P_COST: u32 = 4;
+
+/// 密码错误和数据被篡改无法区分，使用同一个错误提示
+pub(crate) const DECRYPT_ERROR: &str = "密码错误或数据已被篡改！";
+
+/// Argon2id 密钥派生参数，保存在头部
+#[derive(Clone, Debug, Encode, Decode)]
+pub struct KdfParams {
+    pub salt: [u8; SALT_LEN],
+    /// 内存开销(KiB)
+    pub m_cost: u32,
+    /// 迭代次数
+    pub t_cost: u32,
+    /// 并行度
+    pub p_cost: u32,
+}
+
+impl KdfParams {
+    /// 生成随机盐，使用64MiB内存、3次迭代
+    pub fn generate() -> Self {
+        let mut salt = [0; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        Self {
+            salt,
+            m_cost: 64 * 1024,
+            t_cost: 3,
+            p_cost: 1,
+        }
+    }
+
+    /// 检查读取到的参数是否超过上限
+    pub(crate) fn check(&self) -> Result<()> {
+        if self.m_cost > MAX_M_COST || self.t_cost > MAX_T_COST || self.p_cost > MAX_P_COST {
+            return Err(Error::CorruptTrailer("密钥参数错误！".to_string()));
+        }
+        Ok(())
+    }
+
+    /// 从密码派生加密密钥
+    pub fn derive_key(&self, password: &str) -> Result<Key> {
+        self.check()?;
+        let params = Params::new(self.m_cost, self.t_cost, self.p_cost, Some(32))
+            .map_err(|err| Error::CorruptTrailer(format!("密钥参数错误:{}", err)))?;
+        let mut key = Key::default();
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(password.as_bytes(), &self.salt, &mut key)
+            .map_err(|err| Error::CorruptTrailer(format!("密钥派生失败:{}", err)))?;
+        Ok(key)
+    }
+}
+
+/// 生成随机nonce，每个附件使用不同的nonce
+pub(crate) fn generate_nonce() -> [u8; NONCE_LEN] {
+    let mut nonce = [0; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+    nonce
+}
+
+/// 一次性加密一段较短的数据(如头部)，返回随机nonce和密文
+pub(crate) fn seal(key: &Key, data: &[u8]) -> Result<([u8; SEAL_NONCE_LEN], Vec<u8>)> {
+    let mut nonce = [0; SEAL_NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+    let ciphertext = XChaCha20Poly1305::new(key)
+        .encrypt(XNonce::from_slice(&nonce), data)
+        .map_err(|_| Error::InvalidInput("加密失败！".to_string()))?;
+    Ok((nonce, ciphertext))
+}
+
+/// 解密`seal`加密的数据
+pub(crate) fn open(key: &Key, nonce: &[u8; SEAL_NONCE_LEN], ciphertext: &[u8]) -> Result<Vec<u8>> {
+    XChaCha20Poly1305::new(key)
+        .decrypt(XNonce::from_slice(nonce), ciphertext)
+        .map_err(|_| Error::WrongPassword)
+}
+
+fn encrypt_error<E>(_: E) -> io::Error {
+    io::Error::other("加密失败！")
+}
+
+fn decrypt_error<E>(_: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, DecryptError)
+}
+
+/// 分块加密写入，最后必须调用`finish`
+pub(crate) struct EncryptWriter<W: Write> {
+    inner: W,
+    encryptor: Option<EncryptorBE32<XChaCha20Poly1305>>,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> EncryptWriter<W> {
+    pub fn new(inner: W, key: &Key, nonce: &[u8; NONCE_LEN]) -> Self {
+        let aead = XChaCha20Poly1305::new(key);
+        Self {
+            inner,
+            encryptor: Some(EncryptorBE32::from_aead(aead, nonce.into())),
+            buf: Vec::with_capacity(CHUNK_SIZE + 1),
+        }
+    }
+
+    /// 缓存超过一块时，加密并写入一块
+    fn write_chunk(&mut self) -> io::Result<()> {
+        if self.buf.len() > CHUNK_SIZE {
+            let encryptor = self.encryptor.as_mut().expect("write after finish");
+            let chunk = encryptor
+                .encrypt_next(&self.buf[..CHUNK_SIZE])
+                .map_err(encrypt_error)?;
+            self.inner.write_all(&chunk)?;
+            self.buf.drain(..CHUNK_SIZE);
+        }
+        Ok(())
+    }
+
+    /// 加密剩余的数据作为最后一块，返回内部的writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_chunk()?;
+        let encryptor = self.encryptor.take().expect("finish called twice");
+        let data = encryptor
+            .encrypt_last(self.buf.as_slice())
+            .map_err(encrypt_error)?;
+        self.inner.write_all(&data)?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        // 至少多缓存1个字节，保证最后一块由finish写入
+        self.write_chunk()?;
+        let len = data.len().min(CHUNK_SIZE + 1 - self.buf.len());
+        self.buf.extend_from_slice(&data[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// 分块解密读取，`len`为密文总长度
+pub(crate) struct DecryptReader<R: Read> {
+    inner: R,
+    decryptor: Option<DecryptorBE32<XChaCha20Poly1305>>,
+    remaining: u64,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> DecryptReader<R> {
+    pub fn new(inner: R, key: &Key, nonce: &[u8; NONCE_LEN], len: u64) -> Self {
+        let aead = XChaCha20Poly1305::new(key);
+        Self {
+            inner,
+            decryptor: Some(DecryptorBE32::from_aead(aead, nonce.into())),
+            remaining: len,
+            buf: vec![],
+            pos: 0,
+        }
+    }
+
+    fn next_chunk(&mut self) -> io::Result<()> {
+        let decryptor = match self.decryptor.as_mut() {
+            Some(decryptor) => decryptor,
+            None => return Ok(()),
+        };
+        let chunk_len = (CHUNK_SIZE + TAG_LEN) as u64;
+        let last = self.remaining <= chunk_len;
+        let mut chunk = vec![0; self.remaining.min(chunk_len) as usize];
+        self.inner.read_exact(&mut chunk)?;
+        self.remaining -= chunk.len() as u64;
+
+        self.buf = if last {
+            let decryptor = self.decryptor.take().unwrap();
+            decryptor.decrypt_last(chunk.as_slice())
+        } else {
+            decryptor.decrypt_next(chunk.as_slice())
+        }
+        .map_err(decrypt_error)?;
+        self.pos = 0;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.buf.len() {
+            if self.decryptor.is_none() {
+                return Ok(0);
+            }
+            self.next_chunk()?;
+        }
+        let len = out.len().min(self.buf.len() - self.pos);
+        out[..len].copy_from_slice(&self.buf[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+/// 写入数据的同时计算SHA-256
+pub(crate) struct HashWriter<W: Write> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> HashWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// 返回内部的writer和写入数据的摘要
+    pub fn finish(self) -> (W, [u8; HASH_LEN]) {
+        (self.inner, self.hasher.finalize().into())
+    }
+}
+
+impl<W: Write> Write for HashWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(data)?;
+        self.hasher.update(&data[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// 读取数据的同时计算SHA-256，读取结束后由调用者取出摘要
+pub(crate) struct HashReader<'a, R: Read> {
+    inner: R,
+    hasher: &'a mut Sha256,
+}
+
+impl<'a, R: Read> HashReader<'a, R> {
+    pub fn new(inner: R, hasher: &'a mut Sha256) -> Self {
+        Self { inner, hasher }
+    }
+}
+
+impl<R: Read> Read for HashReader<'_, R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(out)?;
+        self.hasher.update(&out[..len]);
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = [7; 32];
+    const NONCE: [u8; NONCE_LEN] = [3; NONCE_LEN];
+
+    fn encrypt(data: &[u8]) -> Vec<u8> {
+        let mut writer = EncryptWriter::new(vec![], Key::from_slice(&KEY), &NONCE);
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap()
+    }
+
+    fn decrypt(ciphertext: &[u8], key: &[u8; 32]) -> Result<Vec<u8>> {
+        let mut reader = DecryptReader::new(
+            ciphertext,
+            Key::from_slice(key),
+            &NONCE,
+            ciphertext.len() as u64,
+        );
+        let mut data = vec![];
+        reader.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    #[test]
+    fn encrypt_chunk_boundaries() {
+        for len in [
+            0,
+            1,
+            CHUNK_SIZE - 1,
+            CHUNK_SIZE,
+            CHUNK_SIZE + 1,
+            2 * CHUNK_SIZE + 1,
+        ] {
+            let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let ciphertext = encrypt(&data);
+            // 每块多出一个认证标签，最后一块可能是整块，空数据也有一块
+            let chunks = len.div_ceil(CHUNK_SIZE).max(1);
+            assert_eq!(ciphertext.len(), len + chunks * TAG_LEN, "{}", len);
+            assert_eq!(decrypt(&ciphertext, &KEY).unwrap(), data, "{}", len);
+        }
+    }
+
+    #[test]
+    fn decrypt_wrong_key() {
+        for len in [0, CHUNK_SIZE, CHUNK_SIZE + 1] {
+            let ciphertext = encrypt(&vec![1; len]);
+            assert!(matches!(
+                decrypt(&ciphertext, &[8; 32]),
+                Err(Error::WrongPassword)
+            ));
+        }
+    }
+
+    #[test]
+    fn decrypt_tampered() {
+        let ciphertext = encrypt(&vec![1; CHUNK_SIZE + 1]);
+        // 修改第一块、最后一块，或者去掉最后一块
+        for pos in [0, ciphertext.len() - 1] {
+            let mut tampered = ciphertext.clone();
+            tampered[pos] ^= 1;
+            assert!(matches!(
+                decrypt(&tampered, &KEY),
+                Err(Error::WrongPassword)
+            ));
+        }
+        let truncated = &ciphertext[..CHUNK_SIZE + TAG_LEN];
+        assert!(matches!(
+            decrypt(truncated, &KEY),
+            Err(Error::WrongPassword)
+        ));
+    }
+
+    #[test]
+    fn seal_round_trip() {
+        let key = Key::from_slice(&KEY);
+        let (nonce, ciphertext) = seal(key, b"manifest").unwrap();
+        assert_eq!(open(key, &nonce, &ciphertext).unwrap(), b"manifest");
+        assert!(matches!(
+            open(Key::from_slice(&[8; 32]), &nonce, &ciphertext),
+            Err(Error::WrongPassword)
+        ));
+    }
+
+    #[test]
+    fn kdf_params_over_limit() {
+        let params = KdfParams::generate();
+        assert!(params.check().is_ok());
+        for forged in [
+            KdfParams {
+                m_cost: MAX_M_COST + 1,
+                ..params.clone()
+            },
+            KdfParams {
+                t_cost: MAX_T_COST + 1,
+                ..params.clone()
+            },
+            KdfParams {
+                p_cost: MAX_P_COST + 1,
+                ..params.clone()
+            },
+        ] {
+            assert!(matches!(
+                forged.derive_key("password"),
+                Err(Error::CorruptTrailer(_))
+            ));
+        }
+    }
+}
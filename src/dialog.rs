@@ -0,0 +1,52 @@
+use hidden_files::{get_file_spec, FileSpec};
+use rfd::FileDialog;
+use slint::SharedString;
+
+use crate::slint_generatedApp;
+
+impl From<&slint_generatedApp::FileSpec> for FileSpec {
+    fn from(f: &slint_generatedApp::FileSpec) -> Self {
+        Self {
+            path: f.path.to_string(),
+            name: f.name.to_string(),
+            size: f.size.to_string().parse().unwrap(),
+            sizemb: f.sizemb.to_string(),
+            extension: f.extension.to_string(),
+        }
+    }
+}
+impl From<&FileSpec> for slint_generatedApp::FileSpec {
+    fn from(f: &FileSpec) -> Self {
+        Self {
+            path: SharedString::from(f.path.clone()),
+            name: SharedString::from(f.name.clone()),
+            size: SharedString::from(format!("{}", f.size)),
+            sizemb: SharedString::from(f.sizemb.clone()),
+            extension: SharedString::from(f.extension.clone()),
+        }
+    }
+}
+
+/// 选择文件
+pub(crate) fn pick_file(filter: Option<(&str, &[&str])>) -> Option<FileSpec> {
+    let mut dlg = FileDialog::new();
+    if let Some((filter_name, extensions)) = filter {
+        dlg = dlg.add_filter(filter_name, extensions);
+    }
+    get_file_spec(dlg.pick_file())
+}
+
+/// 选择多个文件
+pub(crate) fn pick_files() -> Vec<FileSpec> {
+    FileDialog::new()
+        .pick_files()
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|file| get_file_spec(Some(file)))
+        .collect()
+}
+
+/// 选择要附加的文件夹
+pub(crate) fn pick_folder() -> Option<FileSpec> {
+    get_file_spec(FileDialog::new().pick_folder())
+}
@@ -0,0 +1,105 @@
+use std::{fmt, io};
+
+use crate::crypto::DECRYPT_ERROR;
+
+/// 操作失败的原因，`Display`输出本地化的错误提示
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// 操作被取消
+    Cancelled,
+    /// 源文件中没有附件
+    NoAttachment,
+    /// 尾部或头部数据损坏，或者是不支持的格式
+    CorruptTrailer(String),
+    /// 密码错误或者加密数据被篡改，两者无法区分
+    WrongPassword,
+    /// 附件已加密，没有提供密码
+    PasswordRequired,
+    /// 附件或原始源文件的数据校验失败
+    Corrupted(String),
+    /// 解压后的大小超过`ExtractOptions::max_decompressed_size`
+    SizeLimit {
+        name: String,
+        size: u64,
+    },
+    /// 参数错误，如没有附加文件、附件名称重复等
+    InvalidInput(String),
+    Io(io::Error),
+    /// 附件信息解码失败
+    Decode(bincode::error::DecodeError),
+    /// 附件信息编码失败
+    Encode(bincode::error::EncodeError),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Cancelled => write!(f, "操作取消！"),
+            Error::NoAttachment => write!(f, "没有附件！"),
+            Error::WrongPassword => write!(f, "{}", DECRYPT_ERROR),
+            Error::PasswordRequired => write!(f, "附件已加密，需要密码！"),
+            Error::CorruptTrailer(msg) | Error::Corrupted(msg) | Error::InvalidInput(msg) => {
+                write!(f, "{}", msg)
+            }
+            Error::SizeLimit { name, size } => write!(
+                f,
+                "附件{}解压后的大小{}超过限制！",
+                name,
+                crate::get_size_str(*size)
+            ),
+            Error::Io(err) => write!(f, "读写文件失败:{}", err),
+            Error::Decode(err) => write!(f, "附件信息解析失败:{}", err),
+            Error::Encode(err) => write!(f, "附件信息保存失败:{}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            Error::Decode(err) => Some(err),
+            Error::Encode(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// 解密附件数据时的错误，通过`io::Error`传出后再转换为`Error::WrongPassword`
+#[derive(Debug)]
+pub(crate) struct DecryptError;
+
+impl fmt::Display for DecryptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", DECRYPT_ERROR)
+    }
+}
+
+impl std::error::Error for DecryptError {}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        if err
+            .get_ref()
+            .is_some_and(|inner| inner.is::<DecryptError>())
+        {
+            return Error::WrongPassword;
+        }
+        Error::Io(err)
+    }
+}
+
+impl From<bincode::error::DecodeError> for Error {
+    fn from(err: bincode::error::DecodeError) -> Self {
+        Error::Decode(err)
+    }
+}
+
+impl From<bincode::error::EncodeError> for Error {
+    fn from(err: bincode::error::EncodeError) -> Self {
+        Error::Encode(err)
+    }
+}
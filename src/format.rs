@@ -0,0 +1,365 @@
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+
+use crate::{
+    error::Result,
+    layout::Layout,
+    pdf::PDF_MAGIC,
+    png::{self, PNG_MAGIC},
+    progress::{no_progress, Phase},
+    zip::{self, EOCD_MAGIC},
+};
+
+/// 在文件末尾附加数据是否影响源文件的读取
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AppendSafety {
+    /// 读取时忽略末尾的数据，附加后可以正常打开
+    Safe,
+    /// 可以正常打开，但是会影响文件的其他功能，如数字签名失效
+    Caution,
+    /// 部分程序从文件末尾读取，附加后可能无法打开
+    Risky,
+    /// 附加后无法打开，或者无法识别的格式
+    Unsupported,
+}
+
+/// 源文件格式
+#[derive(Debug)]
+pub struct Format {
+    pub name: &'static str,
+    /// 常用扩展名(小写)
+    pub extensions: &'static [&'static str],
+    pub safety: AppendSafety,
+    /// 需要注意、有风险或者不支持的原因
+    pub note: &'static str,
+    /// 根据文件开头的字节判断格式
+    magic: fn(&[u8]) -> bool,
+    /// 查找格式的逻辑结束位置，之后的数据不属于这个格式
+    logical_end: Option<EndFn>,
+}
+
+type EndFn = fn(&mut dyn ReadSeek, u64) -> Result<Option<u64>>;
+
+pub(crate) trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+/// 识别格式时读取的文件开头长度
+const MAGIC_LEN: usize = 16;
+
+/// 已知的格式，按顺序匹配
+pub static FORMATS: &[Format] = &[
+    Format {
+        name: "PNG",
+        extensions: &["png"],
+        safety: AppendSafety::Safe,
+        note: "",
+        magic: |head| head.starts_with(PNG_MAGIC),
+        logical_end: Some(png_end),
+    },
+    Format {
+        name: "JPEG",
+        extensions: &["jpg", "jpeg"],
+        safety: AppendSafety::Safe,
+        note: "",
+        magic: |head| head.starts_with(b"\xff\xd8\xff"),
+        logical_end: Some(jpeg_end),
+    },
+    Format {
+        name: "GIF",
+        extensions: &["gif"],
+        safety: AppendSafety::Safe,
+        note: "",
+        magic: |head| head.starts_with(b"GIF87a") || head.starts_with(b"GIF89a"),
+        logical_end: None,
+    },
+    Format {
+        name: "BMP",
+        extensions: &["bmp"],
+        safety: AppendSafety::Safe,
+        note: "",
+        magic: |head| head.starts_with(b"BM"),
+        logical_end: None,
+    },
+    Format {
+        name: "MP4",
+        extensions: &["mp4", "m4a", "mov"],
+        safety: AppendSafety::Safe,
+        note: "",
+        magic: |head| head.len() >= 8 && &head[4..8] == b"ftyp",
+        logical_end: None,
+    },
+    Format {
+        name: "RAR",
+        extensions: &["rar"],
+        safety: AppendSafety::Safe,
+        note: "",
+        magic: |head| head.starts_with(b"Rar!\x1a\x07"),
+        logical_end: None,
+    },
+    Format {
+        name: "EXE",
+        extensions: &["exe", "dll"],
+        safety: AppendSafety::Caution,
+        note: "程序可以正常运行，但是已签名程序的数字签名(Authenticode)会失效",
+        magic: |head| head.starts_with(b"MZ"),
+        logical_end: None,
+    },
+    Format {
+        name: "PDF",
+        extensions: &["pdf"],
+        safety: AppendSafety::Risky,
+        note: "部分阅读器只在文件末尾查找startxref，附加后可能需要修复才能打开，可以改为保存到文件内部",
+        magic: |head| head.starts_with(PDF_MAGIC),
+        logical_end: None,
+    },
+    Format {
+        name: "ZIP",
+        extensions: &["zip", "jar", "apk", "docx", "xlsx", "pptx"],
+        safety: AppendSafety::Risky,
+        note: "解压程序只在文件末尾64KiB内查找目录，附件较大时可能无法解压，可以改为保存到文件内部",
+        magic: |head| head.starts_with(b"PK\x03\x04") || head.starts_with(EOCD_MAGIC),
+        logical_end: Some(zip_end),
+    },
+    Format {
+        name: "GZIP",
+        extensions: &["gz", "tgz"],
+        safety: AppendSafety::Risky,
+        note: "解压时会提示文件末尾有多余的数据",
+        magic: |head| head.starts_with(b"\x1f\x8b"),
+        logical_end: None,
+    },
+    Format {
+        name: "XZ",
+        extensions: &["xz", "txz"],
+        safety: AppendSafety::Unsupported,
+        note: "xz从文件末尾读取索引，附加后无法解压",
+        magic: |head| head.starts_with(b"\xfd7zXZ\x00"),
+        logical_end: None,
+    },
+];
+
+/// 检查源文件格式的结果
+#[derive(Clone, Debug)]
+pub struct CarrierReport {
+    /// 识别到的格式，无法识别时为None
+    pub format: Option<&'static Format>,
+    /// 原始源文件的长度，不包括已有的附件
+    pub carrier_len: u64,
+    /// 格式的逻辑结束位置，无法确定时为None
+    pub logical_end: Option<u64>,
+    /// 附件的保存方式，保存到文件内部时不提示追加数据的风险
+    pub layout: Layout,
+}
+
+impl CarrierReport {
+    /// 附加数据是否安全，无法识别的格式为不支持，附件保存在文件内部时为安全
+    pub fn safety(&self) -> AppendSafety {
+        match self.format {
+            None => AppendSafety::Unsupported,
+            Some(_) if self.layout != Layout::Append => AppendSafety::Safe,
+            Some(format) => format.safety,
+        }
+    }
+
+    /// 逻辑结束位置之后已经存在的数据长度，不包括本程序保存的附件
+    pub fn trailing_len(&self) -> u64 {
+        self.logical_end
+            .map(|end| self.carrier_len.saturating_sub(end))
+            .unwrap_or(0)
+    }
+
+    /// 需要提示用户的问题
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = vec![];
+        match (self.format, self.safety()) {
+            (None, _) => {
+                warnings.push("无法识别源文件格式，附加数据后可能无法正常打开！".to_string())
+            }
+            (Some(_), AppendSafety::Safe) => {}
+            (Some(format), AppendSafety::Caution) => warnings.push(format!(
+                "{}文件附加数据需要注意:{}",
+                format.name, format.note
+            )),
+            (Some(format), AppendSafety::Risky) => {
+                warnings.push(format!("{}文件附加数据有风险:{}", format.name, format.note))
+            }
+            (Some(format), AppendSafety::Unsupported) => {
+                warnings.push(format!("不支持{}文件:{}", format.name, format.note))
+            }
+        }
+        if let (Some(format), trailing @ 1..) = (self.format, self.trailing_len()) {
+            warnings.push(format!(
+                "源文件在{}结束位置之后已经有{}字节的其他数据，可能已经被其他程序附加过数据！",
+                format.name, trailing
+            ));
+        }
+        warnings
+    }
+}
+
+/// # 根据文件内容识别源文件格式
+///
+/// 参数:
+/// * `reader`: 源文件
+/// * `carrier_len`: 原始源文件的长度，源文件中已有附件时为附件区开始位置
+/// * `layout`: 已有附件的保存方式
+pub(crate) fn detect<R: Read + Seek>(
+    reader: &mut R,
+    carrier_len: u64,
+    layout: Layout,
+) -> Result<CarrierReport> {
+    let mut head = [0; MAGIC_LEN];
+    let head_len = (MAGIC_LEN as u64).min(carrier_len) as usize;
+    reader.seek(SeekFrom::Start(0))?;
+    reader.read_exact(&mut head[..head_len])?;
+    let format = FORMATS
+        .iter()
+        .find(|format| (format.magic)(&head[..head_len]));
+    let logical_end = match format.and_then(|format| format.logical_end) {
+        Some(logical_end) => logical_end(reader, carrier_len)?,
+        None => None,
+    };
+    log::debug!(
+        "源文件格式:{:?} 逻辑结束位置:{:?}",
+        format.map(|f| f.name),
+        logical_end
+    );
+    Ok(CarrierReport {
+        format,
+        carrier_len,
+        logical_end,
+        layout,
+    })
+}
+
+/// 顺序读取文件，记录当前位置，不超过原始源文件的长度
+pub(crate) struct Scanner<'a> {
+    inner: BufReader<&'a mut dyn ReadSeek>,
+    pub pos: u64,
+    len: u64,
+}
+
+impl<'a> Scanner<'a> {
+    pub fn new(reader: &'a mut dyn ReadSeek, start: u64, len: u64) -> io::Result<Self> {
+        reader.seek(SeekFrom::Start(start))?;
+        Ok(Self {
+            inner: BufReader::new(reader),
+            pos: start,
+            len,
+        })
+    }
+
+    /// 读取固定长度，超过结束位置时返回None
+    pub fn read<const N: usize>(&mut self) -> io::Result<Option<[u8; N]>> {
+        if self.pos + N as u64 > self.len {
+            return Ok(None);
+        }
+        let mut buf = [0; N];
+        self.inner.read_exact(&mut buf)?;
+        self.pos += N as u64;
+        Ok(Some(buf))
+    }
+
+    /// 跳过指定长度，超过结束位置时返回false
+    pub fn skip(&mut self, len: u64) -> io::Result<bool> {
+        if self.pos + len > self.len {
+            return Ok(false);
+        }
+        self.inner.seek_relative(len as i64)?;
+        self.pos += len;
+        Ok(true)
+    }
+}
+
+/// PNG：到IEND块(包括校验和)结束
+fn png_end(reader: &mut dyn ReadSeek, len: u64) -> Result<Option<u64>> {
+    let chunks = no_progress(Phase::Inspect, len, |progress| {
+        png::read_chunks(reader, len, progress)
+    })?;
+    Ok(chunks.and_then(|chunks| chunks.last().map(|chunk| chunk.end())))
+}
+
+/// JPEG：依次跳过每个段，扫描数据中查找下一个标记，到EOI结束
+fn jpeg_end(reader: &mut dyn ReadSeek, len: u64) -> Result<Option<u64>> {
+    let mut scanner = Scanner::new(reader, 2, len)?;
+    let mut marker = match scanner.read::<2>()? {
+        Some([0xff, marker]) => marker,
+        _ => return Ok(None),
+    };
+    loop {
+        match marker {
+            0xd9 => return Ok(Some(scanner.pos)),
+            // 没有长度的标记
+            0x01 | 0xd0..=0xd7 => {}
+            _ => {
+                let seg_len = match scanner.read::<2>()? {
+                    Some(seg_len) => u16::from_be_bytes(seg_len) as u64,
+                    None => return Ok(None),
+                };
+                if seg_len < 2 || !scanner.skip(seg_len - 2)? {
+                    return Ok(None);
+                }
+            }
+        }
+        // SOS之后是压缩数据，其中的0xFF后面跟0x00或RST标记
+        let mut prev = match scanner.read::<1>()? {
+            Some([byte]) => byte,
+            None => return Ok(None),
+        };
+        marker = loop {
+            let byte = match scanner.read::<1>()? {
+                Some([byte]) => byte,
+                None => return Ok(None),
+            };
+            if prev == 0xff && !matches!(byte, 0x00 | 0xff | 0xd0..=0xd7) {
+                break byte;
+            }
+            prev = byte;
+        };
+    }
+}
+
+/// ZIP：在末尾查找目录结束记录，到注释结束
+fn zip_end(reader: &mut dyn ReadSeek, len: u64) -> Result<Option<u64>> {
+    Ok(zip::find_eocd(reader, len)?.map(|(_, end)| end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(name: &str, layout: Layout) -> CarrierReport {
+        CarrierReport {
+            format: FORMATS.iter().find(|format| format.name == name),
+            carrier_len: 0,
+            logical_end: None,
+            layout,
+        }
+    }
+
+    #[test]
+    fn in_file_layout_is_safe() {
+        assert_eq!(report("PDF", Layout::Append).safety(), AppendSafety::Risky);
+        assert_eq!(
+            report("PDF", Layout::PdfStream).safety(),
+            AppendSafety::Safe
+        );
+        assert!(report("PDF", Layout::PdfStream).warnings().is_empty());
+        assert_eq!(report("ZIP", Layout::Append).safety(), AppendSafety::Risky);
+        assert_eq!(
+            report("ZIP", Layout::ZipDirectory).safety(),
+            AppendSafety::Safe
+        );
+        assert_eq!(
+            report("XZ", Layout::Append).safety(),
+            AppendSafety::Unsupported
+        );
+    }
+
+    #[test]
+    fn exe_signature_warning() {
+        let report = report("EXE", Layout::Append);
+        assert_eq!(report.safety(), AppendSafety::Caution);
+        assert!(report.warnings()[0].contains("Authenticode"));
+    }
+}
@@ -0,0 +1,267 @@
+use std::io::{self, SeekFrom, Write};
+
+use crate::{
+    error::{Error, Result},
+    format::{ReadSeek, Scanner},
+    layout::Segments,
+    progress::ChunkProgress,
+};
+
+const SOI: [u8; 2] = [0xff, 0xd8];
+const SOS: u8 = 0xda;
+const EOI: u8 = 0xd9;
+const APP0: u8 = 0xe0;
+const APP1: u8 = 0xe1;
+/// 保存附件的段使用APP15，很少有程序使用
+const HIDDEN_MARKER: u8 = 0xef;
+/// 附件段数据开头的标识，用来区分其他程序写入的APP15段
+const HIDDEN_ID: &[u8; 5] = b"hiDn\0";
+/// 附件段数据开头：标识(5) 序号(4)
+const SEGMENT_HEADER_LEN: usize = HIDDEN_ID.len() + 4;
+/// 段长度字段包括自身的2字节，段数据最长65533字节
+const MAX_SEGMENT_LEN: usize = 65533;
+/// 每个附件段最多保存的附件数据长度
+pub(crate) const MAX_SEGMENT_DATA: usize = MAX_SEGMENT_LEN - SEGMENT_HEADER_LEN;
+
+/// JPEG中SOS之前的一个段
+struct Segment {
+    offset: u64,
+    /// 段结束位置
+    end: u64,
+    marker: u8,
+    /// 附件段的序号，不是附件段时为None
+    seq: Option<u32>,
+}
+
+/// 依次读取SOS之前每个段的位置，不是JPEG文件或者段结构错误时返回None
+fn read_segments(
+    reader: &mut dyn ReadSeek,
+    len: u64,
+    progress: &mut ChunkProgress,
+) -> Result<Option<Vec<Segment>>> {
+    let mut soi = [0; 2];
+    if len < soi.len() as u64 {
+        return Ok(None);
+    }
+    reader.seek(SeekFrom::Start(0))?;
+    reader.read_exact(&mut soi)?;
+    if soi != SOI {
+        return Ok(None);
+    }
+    let mut scanner = Scanner::new(reader, SOI.len() as u64, len)?;
+    let mut segments = vec![];
+    loop {
+        let marker = match scanner.read::<2>()? {
+            Some([0xff, marker]) => marker,
+            _ => return Ok(None),
+        };
+        match marker {
+            // SOS之后是压缩数据，附件段只会在SOS之前
+            SOS | EOI => return Ok(Some(segments)),
+            // 没有长度的标记
+            0x01 | 0xd0..=0xd7 => continue,
+            _ => {}
+        }
+        let offset = scanner.pos - 2;
+        let mut remaining = match scanner.read::<2>()? {
+            Some(seg_len) if u16::from_be_bytes(seg_len) >= 2 => {
+                u16::from_be_bytes(seg_len) as u64 - 2
+            }
+            _ => return Ok(None),
+        };
+        let mut seq = None;
+        if marker == HIDDEN_MARKER && remaining >= SEGMENT_HEADER_LEN as u64 {
+            let header = match scanner.read::<SEGMENT_HEADER_LEN>()? {
+                Some(header) => header,
+                None => return Ok(None),
+            };
+            if header.starts_with(HIDDEN_ID) {
+                seq = Some(u32::from_be_bytes([
+                    header[5], header[6], header[7], header[8],
+                ]));
+            }
+            remaining -= SEGMENT_HEADER_LEN as u64;
+        }
+        if !scanner.skip(remaining)? {
+            return Ok(None);
+        }
+        progress.scan(scanner.pos)?;
+        segments.push(Segment {
+            offset,
+            end: scanner.pos,
+            marker,
+            seq,
+        });
+    }
+}
+
+/// # 查找保存附件的段
+///
+/// 返回去掉附件段之后的原始源文件各段，以及附件段中数据的各段，没有附件段时返回None。
+/// 附件段的序号必须从0开始连续，附件数据和头部都有自己的校验值
+pub(crate) fn hidden_segments(
+    reader: &mut dyn ReadSeek,
+    len: u64,
+    progress: &mut ChunkProgress,
+) -> Result<Option<(Segments, Segments)>> {
+    let segments = match read_segments(reader, len, progress)? {
+        Some(segments) => segments,
+        None => return Ok(None),
+    };
+    let mut carrier = vec![];
+    let mut data = vec![];
+    let mut start = 0;
+    for segment in &segments {
+        let seq = match segment.seq {
+            Some(seq) => seq,
+            None => continue,
+        };
+        if seq as usize != data.len() {
+            return Err(Error::CorruptTrailer(format!(
+                "JPEG中的附件段顺序错误:{}",
+                seq
+            )));
+        }
+        carrier.push((start, segment.offset - start));
+        let data_start = segment.offset + 4 + SEGMENT_HEADER_LEN as u64;
+        data.push((data_start, segment.end - data_start));
+        start = segment.end;
+    }
+    if data.is_empty() {
+        return Ok(None);
+    }
+    carrier.push((start, len - start));
+    carrier.retain(|(_, len)| *len > 0);
+    data.retain(|(_, len)| *len > 0);
+    Ok(Some((carrier, data)))
+}
+
+/// 写入一个附件段，包括标记、长度、标识和序号
+pub(crate) fn write_segment(output: &mut dyn Write, seq: u32, data: &[u8]) -> io::Result<()> {
+    let seg_len = (2 + SEGMENT_HEADER_LEN + data.len()) as u16;
+    output.write_all(&[0xff, HIDDEN_MARKER])?;
+    output.write_all(&seg_len.to_be_bytes())?;
+    output.write_all(HIDDEN_ID)?;
+    output.write_all(&seq.to_be_bytes())?;
+    output.write_all(data)
+}
+
+/// 附件段插入的位置：SOI和紧跟在后面的APP0(JFIF)、APP1(Exif)段之后，
+/// 这两种段必须在文件开头
+pub(crate) fn insert_point(
+    reader: &mut dyn ReadSeek,
+    len: u64,
+    progress: &mut ChunkProgress,
+) -> Result<u64> {
+    let segments = read_segments(reader, len, progress)?
+        .ok_or_else(|| Error::InvalidInput("源文件不是有效的JPEG文件！".to_string()))?;
+    let mut insert_at = SOI.len() as u64;
+    for segment in &segments {
+        if segment.offset != insert_at || !matches!(segment.marker, APP0 | APP1) {
+            break;
+        }
+        insert_at = segment.end;
+    }
+    Ok(insert_at)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        layout::{
+            tests::{inspect, round_trip},
+            Layout,
+        },
+        EmbedOptions,
+    };
+
+    fn segment(marker: u8, data: &[u8]) -> Vec<u8> {
+        let mut segment = vec![0xff, marker];
+        segment.extend_from_slice(&(data.len() as u16 + 2).to_be_bytes());
+        segment.extend_from_slice(data);
+        segment
+    }
+
+    /// 1x1的JPEG，`app`为SOI之后的APP段
+    fn jpeg(app: &[(u8, &[u8])]) -> Vec<u8> {
+        let mut jpeg = SOI.to_vec();
+        for (marker, data) in app {
+            jpeg.extend(segment(*marker, data));
+        }
+        jpeg.extend(segment(0xdb, &[0; 65]));
+        jpeg.extend(segment(0xc0, &[8, 0, 1, 0, 1, 1, 1, 0x11, 0]));
+        jpeg.extend(segment(SOS, &[1, 1, 0, 0, 0x3f, 0]));
+        // 压缩数据中的0xFF后面是0x00
+        jpeg.extend_from_slice(&[0x12, 0xff, 0x00, 0x34, 0xff, EOI]);
+        jpeg
+    }
+
+    const JFIF: &[u8] = b"JFIF\0\x01\x01\0\0\x01\0\x01\0\0";
+
+    /// 附件段的开始位置和序号
+    fn hidden(output: &[u8]) -> Vec<(usize, u32)> {
+        let mut segments = vec![];
+        let mut pos = SOI.len();
+        while output[pos + 1] != SOS {
+            let len = u16::from_be_bytes([output[pos + 2], output[pos + 3]]) as usize;
+            if output[pos + 1] == HIDDEN_MARKER && output[pos + 4..].starts_with(HIDDEN_ID) {
+                let seq = &output[pos + 9..pos + 13];
+                segments.push((pos, u32::from_be_bytes(seq.try_into().unwrap())));
+            }
+            pos += 2 + len;
+        }
+        segments
+    }
+
+    #[test]
+    fn jpeg_round_trip() {
+        let carrier = jpeg(&[(APP0, JFIF)]);
+        let output = round_trip(&carrier, Layout::JpegSegment);
+        // 附件段在APP0之后，每段最长65533字节
+        let app0_end = SOI.len() + 4 + JFIF.len();
+        assert!(output.starts_with(&carrier[..app0_end]));
+        let segments = hidden(&output);
+        assert_eq!(segments[0].0, app0_end);
+        assert!(segments.len() > 1_200_000 / MAX_SEGMENT_DATA);
+        assert!(segments
+            .iter()
+            .enumerate()
+            .all(|(i, (_, seq))| *seq == i as u32));
+        assert!(output.ends_with(&carrier[app0_end..]));
+    }
+
+    #[test]
+    fn jpeg_insert_after_exif() {
+        let carrier = jpeg(&[(APP0, JFIF), (APP1, b"Exif\0\0MM")]);
+        let output = round_trip(&carrier, Layout::JpegSegment);
+        let app1_end = SOI.len() + 4 + JFIF.len() + 4 + 8;
+        assert_eq!(hidden(&output)[0].0, app1_end);
+        // 没有APP段时插入到SOI之后
+        let output = round_trip(&jpeg(&[]), Layout::JpegSegment);
+        assert_eq!(hidden(&output)[0].0, SOI.len());
+    }
+
+    #[test]
+    fn jpeg_segment_order() {
+        let carrier = jpeg(&[(APP0, JFIF)]);
+        let options = EmbedOptions {
+            layout: Layout::JpegSegment,
+            ..Default::default()
+        };
+        let mut output = crate::embed_bytes(&carrier, &[("a.txt", b"a")], &options).unwrap();
+        let (pos, _) = hidden(&output)[0];
+        output[pos + 12] = 1;
+        assert!(matches!(inspect(&output), Err(Error::CorruptTrailer(_))));
+    }
+
+    #[test]
+    fn jpeg_invalid_carrier() {
+        let options = EmbedOptions {
+            layout: Layout::JpegSegment,
+            ..Default::default()
+        };
+        let res = crate::embed_bytes(b"not a jpeg", &[("a.txt", b"a")], &options);
+        assert!(matches!(res, Err(Error::InvalidInput(_))));
+    }
+}
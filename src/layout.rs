@@ -0,0 +1,464 @@
+use sha2::{Digest, Sha256};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use crate::{
+    container::{self, Attachments},
+    crypto::HashReader,
+    error::{Error, Result},
+    format::ReadSeek,
+    jpeg, pdf, png,
+    progress::{ChunkProgress, Phase},
+    stream::{Embedder, BUF_SIZE},
+    utils::{copy_chunks, EmbedOptions},
+    zip,
+};
+
+/// 附件在源文件中的保存方式
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Layout {
+    /// 附件、头部和尾部依次追加在源文件末尾
+    #[default]
+    Append,
+    /// 附件、头部和尾部保存在PNG的私有辅助块中，位于IEND之前
+    PngChunk,
+    /// 附件、头部和尾部保存在JPEG的APP15段中，位于SOI和APP0/APP1段之后
+    JpegSegment,
+    /// 附件、头部和尾部保存为PDF增量更新中的嵌入文件流，位于源文件末尾
+    PdfStream,
+    /// 附件、头部和尾部保存在ZIP的中央目录之前，目录结束记录中的偏移随之修改
+    ZipDirectory,
+}
+
+/// 源文件中的几段数据，每段为开始位置和长度
+pub(crate) type Segments = Vec<(u64, u64)>;
+
+/// 拼接后的数据中需要替换的字节，每项为拼接后的位置和替换的内容
+pub(crate) type Patches = Vec<(u64, Vec<u8>)>;
+
+/// # 把源文件中分散的几段数据拼接为连续的数据
+///
+/// 拼接后的结构与追加方式相同：原始源文件 附件 头部 尾部，
+/// 附件信息的读取和附件的提取都不需要区分保存方式
+pub(crate) struct SegmentReader<R> {
+    inner: R,
+    /// 每一段在源文件中的开始位置和长度，按拼接的顺序排列
+    segments: Segments,
+    /// 每一段在拼接后的数据中的开始位置
+    starts: Vec<u64>,
+    /// 读取时替换的字节，用来还原保存附件时修改的源文件数据
+    patches: Patches,
+    len: u64,
+    pos: u64,
+    /// 源文件的当前位置，与需要读取的位置相同时不需要Seek
+    inner_pos: Option<u64>,
+}
+
+impl<R: Read + Seek> SegmentReader<R> {
+    fn new(inner: R, segments: Segments, patches: Patches) -> Self {
+        let mut starts = Vec::with_capacity(segments.len());
+        let mut len = 0;
+        for (_, seg_len) in &segments {
+            starts.push(len);
+            len += seg_len;
+        }
+        Self {
+            inner,
+            segments,
+            starts,
+            patches,
+            len,
+            pos: 0,
+            inner_pos: None,
+        }
+    }
+
+    /// 只保留拼接后数据的前`len`个字节，用来读取原始源文件
+    pub fn limit(mut self, len: u64) -> Self {
+        let mut remaining = len.min(self.len);
+        self.segments.retain_mut(|(_, seg_len)| {
+            *seg_len = (*seg_len).min(remaining);
+            remaining -= *seg_len;
+            *seg_len > 0
+        });
+        Self::new(self.inner, self.segments, self.patches)
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read + Seek> Read for SegmentReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len || out.is_empty() {
+            return Ok(0);
+        }
+        let idx = self.starts.partition_point(|start| *start <= self.pos) - 1;
+        let (seg_start, seg_len) = self.segments[idx];
+        let offset = self.pos - self.starts[idx];
+        let file_pos = seg_start + offset;
+        if self.inner_pos != Some(file_pos) {
+            self.inner.seek(SeekFrom::Start(file_pos))?;
+        }
+        let len = out.len().min((seg_len - offset) as usize);
+        let len = self.inner.read(&mut out[..len])?;
+        if len == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        for (patch_pos, patch) in &self.patches {
+            let start = self.pos.max(*patch_pos);
+            let end = (self.pos + len as u64).min(patch_pos + patch.len() as u64);
+            if start < end {
+                out[(start - self.pos) as usize..(end - self.pos) as usize].copy_from_slice(
+                    &patch[(start - patch_pos) as usize..(end - patch_pos) as usize],
+                );
+            }
+        }
+        self.pos += len as u64;
+        self.inner_pos = Some(file_pos + len as u64);
+        Ok(len)
+    }
+}
+
+impl<R: Read + Seek> Seek for SegmentReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+        self.pos = pos.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative position",
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
+
+/// # 检查移除附件后能否还原源文件
+///
+/// PDF在保存附件之后又被其他程序追加了增量更新时，还原为保存附件之前的PDF会丢失这些修改，返回错误
+pub(crate) fn check_restore(
+    reader: &mut dyn ReadSeek,
+    layout: Layout,
+    chunks: &mut ChunkProgress,
+) -> Result<()> {
+    let len = reader.seek(SeekFrom::End(0))?;
+    match layout {
+        Layout::PdfStream if pdf::modified_after(reader, len, chunks)? => Err(Error::InvalidInput(
+            "PDF在保存附件之后又被其他程序修改过(注释、填写表单、签名等)，移除或者替换附件会丢失这些修改！"
+                .to_string(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// # 按照保存方式打开源文件
+///
+/// 参数:
+/// * `reader`: 源文件
+/// * `layout`: 附件的保存方式，None时根据文件内容判断
+/// * `chunks`: 扫描文件结构时通知进度，并检查是否取消
+pub(crate) fn open<R: Read + Seek>(
+    mut reader: R,
+    layout: Option<Layout>,
+    chunks: &mut ChunkProgress,
+) -> Result<(Layout, SegmentReader<R>)> {
+    let len = reader.seek(SeekFrom::End(0))?;
+    let hidden = match layout {
+        Some(Layout::Append) => None,
+        Some(layout) => Some((
+            layout,
+            hidden_segments(&mut reader, len, layout, chunks)?
+                .ok_or_else(|| Error::CorruptTrailer("没有找到保存附件的数据块！".to_string()))?,
+        )),
+        None => {
+            let mut hidden = None;
+            for layout in [
+                Layout::PngChunk,
+                Layout::JpegSegment,
+                Layout::PdfStream,
+                Layout::ZipDirectory,
+            ] {
+                if let Some(segments) = hidden_segments(&mut reader, len, layout, chunks)? {
+                    hidden = Some((layout, segments));
+                    break;
+                }
+            }
+            hidden
+        }
+    };
+    Ok(match hidden {
+        Some((layout, (carrier, data, patches))) => {
+            log::debug!("附件保存方式:{:?} 数据块数:{}", layout, data.len());
+            let segments = carrier.into_iter().chain(data).collect();
+            (layout, SegmentReader::new(reader, segments, patches))
+        }
+        None => (
+            Layout::Append,
+            SegmentReader::new(reader, vec![(0, len)], vec![]),
+        ),
+    })
+}
+
+/// 查找源文件中保存附件的数据块，返回原始源文件各段、附件数据各段和还原源文件需要替换的字节
+fn hidden_segments(
+    reader: &mut dyn ReadSeek,
+    len: u64,
+    layout: Layout,
+    chunks: &mut ChunkProgress,
+) -> Result<Option<(Segments, Segments, Patches)>> {
+    let segments = match layout {
+        Layout::Append => None,
+        Layout::PngChunk => png::hidden_segments(reader, len, chunks)?,
+        Layout::JpegSegment => jpeg::hidden_segments(reader, len, chunks)?,
+        Layout::PdfStream => pdf::hidden_segments(reader, len, chunks)?,
+        Layout::ZipDirectory => return zip::hidden_segments(reader, len, chunks),
+    };
+    Ok(segments.map(|(carrier, data)| (carrier, data, vec![])))
+}
+
+/// 按照文件内容判断保存方式，并读取附件信息
+pub(crate) fn read_attachments<R: Read + Seek>(
+    reader: R,
+    password: Option<&str>,
+    chunks: &mut ChunkProgress,
+) -> Result<(SegmentReader<R>, Option<Attachments>)> {
+    let (layout, mut reader) = open(reader, None, chunks)?;
+    let attachments =
+        container::read_trailer(&mut reader, password, chunks)?.map(|mut attachments| {
+            attachments.layout = layout;
+            attachments
+        });
+    Ok((reader, attachments))
+}
+
+/// 附件数据按照保存方式分块写入，追加在末尾或者保存为一个流时直接写入，最后必须调用`finish`
+pub(crate) struct BlockWriter<W: Write> {
+    inner: W,
+    layout: Layout,
+    buf: Vec<u8>,
+    /// 已经写入的块数，用作JPEG附件段的序号
+    count: u32,
+    /// 已经写入的附件数据总长度
+    len: u64,
+}
+
+impl<W: Write> BlockWriter<W> {
+    fn new(inner: W, layout: Layout) -> Self {
+        Self {
+            inner,
+            layout,
+            buf: vec![],
+            count: 0,
+            len: 0,
+        }
+    }
+
+    /// 每块最多保存的数据长度，不分块时为None
+    fn max_len(&self) -> Option<usize> {
+        match self.layout {
+            Layout::Append | Layout::PdfStream | Layout::ZipDirectory => None,
+            Layout::PngChunk => Some(png::MAX_CHUNK_DATA),
+            Layout::JpegSegment => Some(jpeg::MAX_SEGMENT_DATA),
+        }
+    }
+
+    fn write_block(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        match self.layout {
+            Layout::Append | Layout::PdfStream | Layout::ZipDirectory => {
+                unreachable!("layout is not buffered")
+            }
+            Layout::PngChunk => png::write_chunk(&mut self.inner, &self.buf)?,
+            Layout::JpegSegment => jpeg::write_segment(&mut self.inner, self.count, &self.buf)?,
+        }
+        self.count += 1;
+        self.buf.clear();
+        Ok(())
+    }
+
+    /// 写入剩余的数据作为最后一块，返回内部的writer
+    fn finish(mut self) -> io::Result<W> {
+        self.write_block()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for BlockWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let len = match self.max_len() {
+            None => self.inner.write(data)?,
+            Some(max_len) => {
+                let len = data.len().min(max_len - self.buf.len());
+                self.buf.extend_from_slice(&data[..len]);
+                if self.buf.len() == max_len {
+                    self.write_block()?;
+                }
+                len
+            }
+        };
+        self.len += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// # 按照保存方式把源文件和附件写入输出
+///
+/// 附件、头部和尾部分块插入到源文件中，插入位置之后的源文件数据在附件之后写入，
+/// PDF增量更新的对象和交叉引用段写在附件前后，ZIP中央目录之后的记录修改偏移后写入。
+/// 原始源文件的校验值按照完整的源文件计算，移除附件时去掉附件块即可还原
+///
+/// 参数:
+/// * `carrier`: 原始源文件，不能包含附件
+/// * `output`: 输出
+/// * `options`: 加密、压缩、保存方式等选项
+/// * `chunks`: 进度，总长度为源文件和附件的长度之和
+/// * `add`: 添加附件
+pub(crate) fn embed<R: Read + Seek, W: Write>(
+    carrier: &mut R,
+    output: W,
+    options: &EmbedOptions,
+    chunks: &mut ChunkProgress,
+    add: impl FnOnce(&mut Embedder<BlockWriter<W>>, &mut ChunkProgress) -> Result<()>,
+) -> Result<W> {
+    let len = carrier.seek(SeekFrom::End(0))?;
+    let update = match options.layout {
+        Layout::PdfStream => Some(pdf::Update::read(carrier, len, chunks)?),
+        _ => None,
+    };
+    let directory = match options.layout {
+        Layout::ZipDirectory => Some(zip::Directory::read(carrier, len)?),
+        _ => None,
+    };
+    let insert_at = match options.layout {
+        Layout::Append | Layout::PdfStream => len,
+        Layout::PngChunk => png::insert_point(carrier, len, chunks)?,
+        Layout::JpegSegment => jpeg::insert_point(carrier, len, chunks)?,
+        Layout::ZipDirectory => directory.as_ref().map_or(len, |dir| dir.insert_point()),
+    };
+    log::debug!("附件保存方式:{:?} 插入位置:{}", options.layout, insert_at);
+    let mut buf = vec![0; BUF_SIZE];
+
+    let mut output = BlockWriter::new(output, options.layout);
+    let mut hasher = Sha256::new();
+    carrier.seek(SeekFrom::Start(0))?;
+    copy_chunks(
+        &mut HashReader::new((&mut *carrier).take(insert_at), &mut hasher),
+        &mut output.inner,
+        &mut buf,
+        chunks,
+    )?;
+    // 插入位置之后的数据在附件之后才写入，先计算完整的校验值
+    io::copy(
+        &mut HashReader::new((&mut *carrier).take(len - insert_at), &mut hasher),
+        &mut io::sink(),
+    )?;
+    if let Some(update) = &update {
+        update.write_start(&mut output.inner)?;
+    }
+    chunks.set_phase(Phase::Embed);
+    let mut embedder = Embedder::for_layout(output, options)?;
+    embedder.set_carrier_sha256(Some(hasher.finalize().into()));
+    add(&mut embedder, chunks)?;
+    let output = embedder.finish()?;
+    let data_len = output.len;
+    let mut output = output.finish()?;
+    if let Some(update) = &update {
+        update.write_end(&mut output, data_len)?;
+    }
+    // ZIP中央目录直接复制，之后的记录需要修改偏移
+    let copy_end = directory.as_ref().map_or(len, |dir| dir.end_start());
+    if let Some(directory) = &directory {
+        directory.write_marker(&mut output, data_len)?;
+    }
+    carrier.seek(SeekFrom::Start(insert_at))?;
+    copy_chunks(
+        &mut (&mut *carrier).take(copy_end - insert_at),
+        &mut output,
+        &mut buf,
+        chunks,
+    )?;
+    if let Some(directory) = &directory {
+        directory.write_end(carrier, &mut output, data_len)?;
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::{
+        progress::no_progress, stream::extract_bytes, Codec, ExtractOptions, Extractor,
+        VerifyStatus,
+    };
+
+    /// 读取附件信息，没有附件时返回None
+    pub(crate) fn inspect(data: &[u8]) -> Result<Option<Attachments>> {
+        no_progress(Phase::Inspect, 0, |chunks| {
+            read_attachments(Cursor::new(data), None, chunks)
+        })
+        .map(|(_, attachments)| attachments)
+    }
+
+    /// # 保存、读取、提取、校验、还原一次，返回保存附件后的文件
+    ///
+    /// 附件不压缩并且超过1MB，保存到文件内部时会分成多个块或者段
+    pub(crate) fn round_trip(carrier: &[u8], layout: Layout) -> Vec<u8> {
+        let big: Vec<u8> = (0..1_200_000u32).map(|i| (i % 251) as u8).collect();
+        let files: [(&str, &[u8]); 3] = [("a.txt", b"hello"), ("empty", b""), ("big.bin", &big)];
+        let options = EmbedOptions {
+            compression: Codec::None,
+            layout,
+            ..Default::default()
+        };
+        let output = crate::embed_bytes(carrier, &files, &options).unwrap();
+
+        let attachments = inspect(&output).unwrap().unwrap();
+        assert_eq!(attachments.layout, layout);
+        assert_eq!(attachments.carrier_len(), carrier.len() as u64);
+
+        let extracted = extract_bytes(&output, &ExtractOptions::default())
+            .unwrap()
+            .unwrap();
+        assert_eq!(extracted.len(), files.len());
+        for ((entry, data), (name, expected)) in extracted.iter().zip(files) {
+            assert_eq!(entry.name, name);
+            assert_eq!(data, expected);
+        }
+
+        let mut extractor = Extractor::new(Cursor::new(&output), &ExtractOptions::default())
+            .unwrap()
+            .unwrap();
+        let status = extractor.verify().unwrap();
+        assert!(status.iter().all(|(_, s)| *s == VerifyStatus::Ok));
+        let mut original = vec![];
+        extractor.carrier(&mut original).unwrap();
+        assert!(original == carrier, "还原后的源文件不一致");
+        output
+    }
+
+    #[test]
+    fn append_round_trip() {
+        let carrier = vec![0x42; 10_000];
+        let output = round_trip(&carrier, Layout::Append);
+        assert!(output.starts_with(&carrier));
+        assert!(round_trip(b"", Layout::Append).len() > 1_200_000);
+    }
+
+    #[test]
+    fn no_attachment() {
+        assert!(inspect(b"").unwrap().is_none());
+        assert!(inspect(&[0x42; 10_000]).unwrap().is_none());
+    }
+}
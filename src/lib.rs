@@ -0,0 +1,276 @@
+//! 把文件隐藏到图片等文件末尾
+//!
+//! 文件结构：源文件字节 附件1字节 附件2字节 ... 头部 尾部，
+//! 源文件本身的内容不变，仍然可以正常打开。
+
+mod codec;
+mod container;
+mod crypto;
+mod error;
+mod format;
+mod jpeg;
+mod layout;
+mod pdf;
+mod png;
+mod progress;
+mod stream;
+mod tree;
+mod utils;
+mod zip;
+
+use std::{
+    fs::File,
+    io::{Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use progress::{no_progress, ChunkProgress};
+
+pub use codec::Codec;
+pub use container::{Attachments, Entry, EntryKind};
+pub use crypto::KdfParams;
+pub use error::{Error, Result};
+pub use format::{AppendSafety, CarrierReport, Format, FORMATS};
+pub use layout::Layout;
+pub use progress::{CancelToken, NoProgress, Phase, Progress, ProgressEvent};
+pub use stream::{embed_bytes, extract_bytes, Embedder, ExtractedFile, Extractor};
+pub use utils::{
+    get_extension, get_file_name, get_file_spec, get_size_str, EmbedOptions, ExtractOptions,
+    FileSpec, VerifyStatus,
+};
+
+/// 路径转为字符串，不支持非UTF-8的路径
+fn path_str(path: &Path) -> Result<&str> {
+    path.to_str()
+        .ok_or_else(|| Error::InvalidInput(format!("不支持的路径:{}", path.display())))
+}
+
+/// 读取文件或文件夹的信息
+fn file_spec(path: &Path) -> Result<FileSpec> {
+    get_file_spec(Some(path.to_path_buf()))
+        .ok_or_else(|| Error::InvalidInput(format!("无法读取文件:{}", path.display())))
+}
+
+/// # 读取源文件中的附件信息
+///
+/// 没有附件时返回None，附件信息加密时需要提供密码，否则返回的`Attachments::sealed`为true
+///
+/// 参数:
+/// * `carrier`: 源文件路径
+/// * `password`: 附件加密时使用的密码
+/// * `progress`: 接收进度事件
+/// * `cancel`: 取消令牌，用来检测操作是否取消
+pub fn inspect<P: Progress>(
+    carrier: &Path,
+    password: Option<&str>,
+    progress: &P,
+    cancel: &CancelToken,
+) -> Result<Option<Attachments>> {
+    let src_file = File::open(carrier)?;
+    let mut chunks =
+        ChunkProgress::new(Phase::Inspect, src_file.metadata()?.len(), progress, cancel);
+    chunks.check()?;
+    let (_, attachments) = layout::read_attachments(src_file, password, &mut chunks)?;
+    chunks.finish();
+    Ok(attachments)
+}
+
+/// # 根据文件内容检查源文件格式
+///
+/// 判断附加数据是否影响源文件的读取，并查找格式结束位置之后已经存在的数据。
+/// 源文件中已有附件时只检查原始源文件
+///
+/// 参数:
+/// * `carrier`: 源文件路径
+pub fn check_carrier(carrier: &Path) -> Result<CarrierReport> {
+    let (layout, mut src_file) = no_progress(Phase::Inspect, 0, |chunks| {
+        layout::open(File::open(carrier)?, None, chunks)
+    })?;
+    let file_len = src_file.seek(SeekFrom::End(0))?;
+    let attachments = no_progress(Phase::Inspect, 0, |chunks| {
+        container::read_trailer(&mut src_file, None, chunks)
+    })
+    .ok()
+    .flatten();
+    let carrier_len = attachments
+        .as_ref()
+        .map(|attachments| attachments.data_start)
+        .unwrap_or(file_len);
+    let mut report = format::detect(&mut src_file, carrier_len, layout)?;
+    // 附件信息加密时不知道附件区的开始位置，无法判断是否有其他数据
+    if attachments.is_some_and(|attachments| attachments.sealed) {
+        report.logical_end = None;
+    }
+    Ok(report)
+}
+
+/// # 把附件保存到源文件中
+///
+/// 源文件中已有附件时替换已有的附件，不会在旧附件后面再追加。
+/// 保存的路径指向源文件(包括符号链接和硬链接)时直接修改源文件，指向附件时返回错误
+///
+/// 参数:
+/// * `carrier`: 源文件路径
+/// * `attachments`: 附加文件列表，可以是文件夹
+/// * `output`: 保存的路径，None时直接修改源文件
+/// * `options`: 加密、压缩等选项
+/// * `progress`: 接收进度事件
+/// * `cancel`: 取消令牌，用来检测操作是否取消
+pub fn embed<P: Progress>(
+    carrier: &Path,
+    attachments: &[PathBuf],
+    output: Option<&Path>,
+    options: &EmbedOptions,
+    progress: &P,
+    cancel: &CancelToken,
+) -> Result<()> {
+    let src_file_spec = file_spec(carrier)?;
+    let append_file_specs = attachments
+        .iter()
+        .map(|path| file_spec(path))
+        .collect::<Result<Vec<_>>>()?;
+    // 保存到源文件时不能先创建输出文件，否则会在读取之前截断源文件
+    let output = output.filter(|output| !utils::is_same_file(output, carrier));
+    if let Some(output) = output {
+        utils::check_output(output, &append_file_specs)?;
+    }
+    let existing = inspect(carrier, options.password.as_deref(), progress, cancel)?;
+    // 只有追加在末尾时才能直接修改源文件，其他保存方式通过临时文件重写源文件
+    let rewrite = options.layout != Layout::Append
+        || existing
+            .as_ref()
+            .is_some_and(|existing| existing.layout != Layout::Append);
+    let output = output.or(rewrite.then_some(carrier));
+    let output = output.map(path_str).transpose()?;
+
+    match (existing, output) {
+        (existing, Some(output)) => utils::copy_file(
+            &src_file_spec,
+            existing.as_ref(),
+            &append_file_specs,
+            output,
+            options,
+            progress,
+            cancel,
+        ),
+        (Some(existing), None) => utils::update_file(
+            &src_file_spec,
+            &existing,
+            &append_file_specs,
+            options,
+            progress,
+            cancel,
+        ),
+        (None, None) => utils::append_file(
+            &src_file_spec,
+            &append_file_specs,
+            options,
+            progress,
+            cancel,
+        ),
+    }
+}
+
+/// # 提取一个附件到指定文件
+///
+/// 提取的路径不能是源文件
+///
+/// 参数:
+/// * `carrier`: 源文件路径
+/// * `attachments`: `inspect`读取到的附件信息
+/// * `entry`: 要提取的附件
+/// * `output`: 提取到的路径
+/// * `options`: 密码、解压大小限制等选项
+/// * `progress`: 接收进度事件
+/// * `cancel`: 取消令牌，用来检测操作是否取消
+pub fn extract<P: Progress>(
+    carrier: &Path,
+    attachments: &Attachments,
+    entry: &Entry,
+    output: &Path,
+    options: &ExtractOptions,
+    progress: &P,
+    cancel: &CancelToken,
+) -> Result<()> {
+    utils::extract_file(
+        path_str(carrier)?,
+        attachments,
+        entry,
+        path_str(output)?,
+        options,
+        progress,
+        cancel,
+    )
+}
+
+/// # 提取全部附件到指定文件夹，文件夹中的附件保持原来的目录结构
+///
+/// 参数:
+/// * `carrier`: 源文件路径
+/// * `attachments`: `inspect`读取到的附件信息
+/// * `output_dir`: 保存附件的文件夹
+/// * `options`: 密码、解压大小限制等选项
+/// * `progress`: 接收进度事件
+/// * `cancel`: 取消令牌，用来检测操作是否取消
+pub fn extract_all<P: Progress>(
+    carrier: &Path,
+    attachments: &Attachments,
+    output_dir: &Path,
+    options: &ExtractOptions,
+    progress: &P,
+    cancel: &CancelToken,
+) -> Result<()> {
+    utils::extract_all(
+        path_str(carrier)?,
+        attachments,
+        path_str(output_dir)?,
+        options,
+        progress,
+        cancel,
+    )
+}
+
+/// # 移除附件，把源文件还原为附加附件之前的内容
+///
+/// 还原的路径指向源文件时直接截断源文件
+///
+/// 参数:
+/// * `carrier`: 源文件路径
+/// * `attachments`: `inspect`读取到的附件信息
+/// * `output`: 还原后保存的路径，None时直接截断源文件
+/// * `progress`: 接收进度事件
+/// * `cancel`: 取消令牌，用来检测操作是否取消
+pub fn strip<P: Progress>(
+    carrier: &Path,
+    attachments: &Attachments,
+    output: Option<&Path>,
+    progress: &P,
+    cancel: &CancelToken,
+) -> Result<()> {
+    let output = output.filter(|output| !utils::is_same_file(output, carrier));
+    utils::strip(
+        path_str(carrier)?,
+        attachments,
+        output.map(path_str).transpose()?,
+        progress,
+        cancel,
+    )
+}
+
+/// # 校验全部附件，不写入任何文件
+///
+/// 返回每个附件的名称和校验结果，附件加密时也不需要密码
+///
+/// 参数:
+/// * `carrier`: 源文件路径
+/// * `attachments`: `inspect`读取到的附件信息
+/// * `progress`: 接收进度事件
+/// * `cancel`: 取消令牌，用来检测操作是否取消
+pub fn verify<P: Progress>(
+    carrier: &Path,
+    attachments: &Attachments,
+    progress: &P,
+    cancel: &CancelToken,
+) -> Result<Vec<(String, VerifyStatus)>> {
+    utils::verify(path_str(carrier)?, attachments, progress, cancel)
+}
@@ -1,14 +1,25 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
+use hidden_files::{CancelToken, Codec, EntryKind, Layout, NoProgress, ProgressEvent};
 use rfd::FileDialog;
-use slint::{SharedString, Weak};
-use std::sync::{Arc, RwLock};
+use slint::{Model, ModelRc, SharedString, VecModel, Weak};
+use std::{
+    cell::RefCell,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicI32, Ordering},
+};
 
-mod utils;
+mod dialog;
 
 slint::slint! {
     import { App } from "ui/app.slint";
 }
+
+thread_local! {
+    // 当前任务的取消令牌，只在UI线程访问
+    static CURRENT_JOB: RefCell<CancelToken> = RefCell::new(CancelToken::new());
+}
+
 fn main() {
     let app = App::new();
 
@@ -18,12 +29,21 @@ fn main() {
     let handle_weak = app.as_weak();
     app.on_pick_file_calback(move |idx, file_spec| set_pick_file(&handle_weak, idx, file_spec));
 
+    let handle_weak = app.as_weak();
+    app.on_clear_attachments(move || clear_attachments(&handle_weak));
+
     let handle_weak = app.as_weak();
     app.on_save_file(move || save_file(&handle_weak));
 
     let handle_weak = app.as_weak();
     app.on_extract_file(move || extract_file(&handle_weak));
 
+    let handle_weak = app.as_weak();
+    app.on_verify_file(move || verify_file(&handle_weak));
+
+    let handle_weak = app.as_weak();
+    app.on_strip_file(move || strip_file(&handle_weak));
+
     // 取消保存方法
     let handle_weak = app.as_weak();
     app.on_cancel_job(move || cancel_job(&handle_weak));
@@ -42,6 +62,28 @@ fn set_waitting_from_thread(handle_weak: &Weak<App>, waitting: bool) {
 /// 取消操作
 fn cancel_job(handle_weak: &Weak<App>) {
     handle_weak.unwrap().set_user_canceled(true);
+    CURRENT_JOB.with(|job| job.borrow().cancel());
+}
+
+/// 开始新任务，在UI线程调用，返回传给后台线程的取消令牌
+fn new_job() -> CancelToken {
+    let cancel = CancelToken::new();
+    CURRENT_JOB.with(|job| *job.borrow_mut() = cancel.clone());
+    cancel
+}
+
+/// 通知UI线程当前进度，百分比变化时才更新界面
+fn ui_progress(handle_weak: Weak<App>) -> impl Fn(&ProgressEvent) {
+    let last = AtomicI32::new(-1);
+    move |event| {
+        let percent = event.percent();
+        if last.swap(percent, Ordering::Relaxed) != percent {
+            let handle_copy = handle_weak.clone();
+            let _ = slint::invoke_from_event_loop(move || {
+                handle_copy.unwrap().set_current_progress(percent);
+            });
+        }
+    }
 }
 
 /// 弹出文件选择对话框
@@ -55,22 +97,20 @@ fn pick_file(idx: i32, handle_weak: &Weak<App>) {
     let handle_clone = handle_weak.clone();
     std::thread::spawn(move || {
         let res = if idx == 0 {
-            //经过测试在文件末尾写入数据不影响文件读取的类型
-            utils::pick_file(Some((
-                "文件",
-                &[
-                    "bmp", "png", "jpg", "gif", "exe", "pdf", "jar", "rar", "mp4",
-                ],
-            )))
+            //源文件不限制扩展名，选择后根据文件内容检查格式
+            dialog::pick_file(None).into_iter().collect()
+        } else if idx == 3 {
+            //附加整个文件夹
+            dialog::pick_folder().into_iter().collect()
         } else {
-            //附加文件不限制类型
-            utils::pick_file(None)
+            //附加文件不限制类型，可以多选
+            dialog::pick_files()
         };
         let _ = slint::invoke_from_event_loop(move || {
             let handle = handle_clone.unwrap();
             handle.set_waitting(false);
-            if let Some(file_spec) = res {
-                let _ = handle.invoke_pick_file_calback(idx, FileSpec::from(&file_spec));
+            for file_spec in res {
+                handle.invoke_pick_file_calback(idx, FileSpec::from(&file_spec));
             }
         });
     });
@@ -81,40 +121,150 @@ fn set_pick_file(handle_weak: &Weak<App>, idx: i32, file_spec: FileSpec) {
     let handle = handle_weak.unwrap();
     if idx == 0 {
         handle.set_first_file(file_spec.clone());
+        let path = Path::new(file_spec.path.as_str());
+
+        //检查源文件格式是否适合附加数据
+        let report = hidden_files::check_carrier(path).ok();
+        let format = report
+            .as_ref()
+            .and_then(|report| report.format)
+            .map(|format| format.name)
+            .unwrap_or_default();
+        handle.set_carrier_format(format.into());
+        let mut warnings = report.map(|report| report.warnings()).unwrap_or_default();
 
         //检查是否存在附加文件
-        if let Ok(Some(_)) = utils::check_file(&utils::FileSpec::from(&file_spec)) {
-            handle.set_has_attachment(true);
+        let attachments = hidden_files::inspect(path, None, &NoProgress, &CancelToken::new());
+        handle.set_has_attachment(matches!(attachments, Ok(Some(_))));
+        if let Ok(Some(attachments)) = attachments {
+            warnings.extend(attachments.warnings);
+        }
+        if !warnings.is_empty() {
+            alert(&handle, &warnings.join("\n"), |_| {});
         }
     } else {
-        handle.set_second_file(file_spec);
+        //同名的附件只保留最后选择的
+        let mut attachments: Vec<FileSpec> = handle
+            .get_attachments()
+            .iter()
+            .filter(|f| f.name != file_spec.name)
+            .collect();
+        attachments.push(file_spec);
+        handle.set_attachments(ModelRc::new(VecModel::from(attachments)));
     }
 }
 
+/// 清空附件列表
+fn clear_attachments(handle_weak: &Weak<App>) {
+    let handle = handle_weak.unwrap();
+    if handle.get_waitting() {
+        return;
+    }
+    handle.set_attachments(ModelRc::default());
+}
+
 /// 保存
 fn save_file(handle_weak: &Weak<App>) {
     let handle = handle_weak.unwrap();
-    if handle.get_first_file().name.len() == 0
-        || handle.get_second_file().name.len() == 0
+    if handle.get_first_file().name.is_empty()
+        || handle.get_attachments().row_count() == 0
         || handle.get_waitting()
     {
         return;
     }
 
-    let first_file = utils::FileSpec::from(&handle.get_first_file());
-    let append_file = utils::FileSpec::from(&handle.get_second_file());
+    let first_file = hidden_files::FileSpec::from(&handle.get_first_file());
+    let password = get_password(&handle);
+    let options = hidden_files::EmbedOptions {
+        // 没有密码时不能加密附件信息
+        encrypt_metadata: password.is_some() && handle.get_encrypt_metadata(),
+        password,
+        keep_metadata: handle.get_keep_metadata(),
+        compression: if handle.get_compress() {
+            Codec::Zstd
+        } else {
+            Codec::None
+        },
+        // 源文件是PNG、JPEG、PDF或ZIP时可以把附件保存到文件内部，按照文件内容识别的格式选择，不看扩展名
+        layout: match handle.get_carrier_format().as_str() {
+            "PNG" if handle.get_in_file() => Layout::PngChunk,
+            "JPEG" if handle.get_in_file() => Layout::JpegSegment,
+            "PDF" if handle.get_in_file() => Layout::PdfStream,
+            "ZIP" if handle.get_in_file() => Layout::ZipDirectory,
+            _ => Layout::Append,
+        },
+    };
+    let append_files: Vec<PathBuf> = handle
+        .get_attachments()
+        .iter()
+        .map(|f| PathBuf::from(f.path.as_str()))
+        .collect();
+    let in_place = handle.get_in_place();
+
+    // 源文件中已有附件时替换已有的附件，不在旧附件后面再追加
+    if !handle.get_has_attachment() {
+        start_save(handle_weak, first_file, append_files, options, in_place);
+        return;
+    }
+    match hidden_files::inspect(
+        Path::new(&first_file.path),
+        options.password.as_deref(),
+        &NoProgress,
+        &CancelToken::new(),
+    ) {
+        Ok(Some(attachments)) if attachments.sealed => {
+            alert(&handle, "已有附件信息已加密，请输入原来的密码！", |_| {});
+            return;
+        }
+        Ok(_) => {}
+        Err(err) => {
+            alert(&handle, &error_message(&err), |_| {});
+            return;
+        }
+    }
     let handle_clone = handle_weak.clone();
+    confirm(
+        &handle,
+        "源文件中已有附件，确定替换已有附件吗？",
+        move |confirm| {
+            if confirm {
+                start_save(
+                    &handle_clone,
+                    first_file.clone(),
+                    append_files.clone(),
+                    options.clone(),
+                    in_place,
+                );
+            }
+        },
+    );
+}
 
-    handle.set_user_canceled(false);
+/// 开始保存，源文件中已有的附件会被替换
+fn start_save(
+    handle_weak: &Weak<App>,
+    first_file: hidden_files::FileSpec,
+    append_files: Vec<PathBuf>,
+    options: hidden_files::EmbedOptions,
+    in_place: bool,
+) {
+    let handle_clone = handle_weak.clone();
+    handle_weak.unwrap().set_user_canceled(false);
 
+    let cancel = new_job();
     std::thread::spawn(move || {
         set_waitting_from_thread(&handle_clone, true);
-        let res = utils::get_file_name(
-            FileDialog::new()
-                .set_file_name(&first_file.name)
-                .add_filter(&first_file.extension, &[&first_file.extension])
-                .save_file(),
-        );
+        // 修改源文件时不需要选择保存路径
+        let res = if in_place {
+            Some((first_file.name.clone(), first_file.path.clone()))
+        } else {
+            hidden_files::get_file_name(
+                FileDialog::new()
+                    .set_file_name(&first_file.name)
+                    .add_filter(&first_file.extension, &[&first_file.extension])
+                    .save_file(),
+            )
+        };
 
         let handle_clone1 = handle_clone.clone();
         let (output_file_name, _) = res.clone().unwrap_or((String::new(), String::new()));
@@ -123,7 +273,7 @@ fn save_file(handle_weak: &Weak<App>) {
             let handle = handle_clone1.unwrap();
             // 选择文件完成后，就要设置非模式状态，以便处理取消操作
             handle.set_waitting(false);
-            if output_file_name.len() > 0 {
+            if !output_file_name.is_empty() {
                 handle.set_output_file(SharedString::from(output_file_name));
                 handle.set_current_progress(0);
                 handle.set_show_progress(true);
@@ -132,37 +282,24 @@ fn save_file(handle_weak: &Weak<App>) {
 
         if let Some((_, output_file_path)) = res {
             // 开始保存文件
-            let handle_clone2 = handle_clone.clone();
-            // 在UI线程访问这个变量
-            let is_cancled = Arc::new(RwLock::new(false));
-            let ui_is_cancled = is_cancled.clone();
+            let progress = ui_progress(handle_clone.clone());
             let mut copy_success = true;
-
-            let copy_res = utils::copy_file(
-                &first_file,
-                &append_file,
-                &output_file_path,
-                move |progress| {
-                    let handle_copy = handle_clone2.clone();
-                    let ui_is_cancled_copy = ui_is_cancled.clone();
-                    //通知UI线程当前进度
-                    let _ = slint::invoke_from_event_loop(move || {
-                        let handle = handle_copy.unwrap();
-                        handle.set_current_progress(progress);
-                        //是否取消了当前操作
-                        if let (true, Ok(mut ui_is_cancled)) =
-                            (handle.get_user_canceled(), ui_is_cancled_copy.write())
-                        {
-                            *ui_is_cancled = true;
-                        }
-                    });
+            let copy_res = hidden_files::embed(
+                Path::new(&first_file.path),
+                &append_files,
+                if in_place {
+                    None
+                } else {
+                    Some(Path::new(&output_file_path))
                 },
-                is_cancled,
+                &options,
+                &progress,
+                &cancel,
             );
 
             let msg = if let Err(err) = copy_res {
                 copy_success = false;
-                format!("{:?}", err)
+                error_message(&err)
             } else {
                 "文件保存成功！".to_string()
             };
@@ -177,7 +314,8 @@ fn save_file(handle_weak: &Weak<App>) {
                 if copy_success {
                     //复制成功，清空文件
                     handle.set_first_file(slint_generatedApp::FileSpec::default());
-                    handle.set_second_file(slint_generatedApp::FileSpec::default());
+                    handle.set_attachments(ModelRc::default());
+                    handle.set_has_attachment(false);
                 }
                 alert(&handle, &msg, |_| {});
             });
@@ -188,45 +326,81 @@ fn save_file(handle_weak: &Weak<App>) {
 /// 提取文件
 fn extract_file(handle_weak: &Weak<App>) {
     let handle = handle_weak.unwrap();
-    if handle.get_first_file().name.len() == 0 || handle.get_waitting() {
+    if handle.get_first_file().name.is_empty() || handle.get_waitting() {
         return;
     }
 
-    let first_file = utils::FileSpec::from(&handle.get_first_file());
+    let first_file = hidden_files::FileSpec::from(&handle.get_first_file());
+    let password = get_password(&handle);
 
     //读取文件信息
-    let attachment_res = utils::check_file(&first_file);
-    if attachment_res.is_err() {
-        alert(&handle, &format!("{:?}", attachment_res.err()), |_| {});
-        return;
-    }
-    let attachment_res = attachment_res.unwrap();
-    if attachment_res.is_none() {
-        alert(&handle, "没有附件！", |_| {});
+    let attachments = match hidden_files::inspect(
+        Path::new(&first_file.path),
+        password.as_deref(),
+        &NoProgress,
+        &CancelToken::new(),
+    ) {
+        // 附件信息加密时entries为空，需要先判断
+        Ok(Some(attachments)) if attachments.sealed => {
+            alert(&handle, "附件信息已加密，请输入密码！", |_| {});
+            return;
+        }
+        Ok(Some(attachments)) if !attachments.entries.is_empty() => attachments,
+        Ok(_) => {
+            alert(&handle, "没有附件！", |_| {});
+            return;
+        }
+        Err(err) => {
+            alert(&handle, &error_message(&err), |_| {});
+            return;
+        }
+    };
+    if attachments.is_encrypted() && password.is_none() {
+        alert(&handle, "附件已加密，请输入密码！", |_| {});
         return;
     }
-    let (attachment_file_spec, start_offset, end_offset) = attachment_res.unwrap();
     let handle_clone = handle_weak.clone();
-    let attachment_info = format!(
-        "附件:{} 大小:{} 确定提取文件吗？",
-        attachment_file_spec.name, attachment_file_spec.sizemb
-    );
+    let attachment_info = if let [entry] = attachments.entries.as_slice() {
+        format!(
+            "附件:{} 大小:{} 确定提取文件吗？",
+            entry.name,
+            hidden_files::get_size_str(entry.size)
+        )
+    } else {
+        let total = attachments.entries.iter().map(|e| e.size).sum();
+        format!(
+            "共{}个附件 大小:{} 确定提取到文件夹吗？",
+            attachments.entries.len(),
+            hidden_files::get_size_str(total)
+        )
+    };
     confirm(&handle, &attachment_info, move |confirm| {
         let handle_clone = handle_clone.clone();
-        let attachment_file_spec = attachment_file_spec.clone();
+        let attachments = attachments.clone();
         let first_file = first_file.clone();
+        let options = hidden_files::ExtractOptions {
+            password: password.clone(),
+            ..Default::default()
+        };
         if confirm {
+            let cancel = new_job();
             std::thread::spawn(move || {
                 set_waitting_from_thread(&handle_clone, true);
-                let res = utils::get_file_name(
-                    FileDialog::new()
-                        .set_file_name(&attachment_file_spec.name)
-                        .add_filter(
-                            &attachment_file_spec.extension,
-                            &[&attachment_file_spec.extension],
-                        )
-                        .save_file(),
-                );
+                // 只有一个文件时保存为文件，多个附件或者文件夹时选择保存的文件夹
+                let single = match attachments.entries.as_slice() {
+                    [entry] if entry.kind == EntryKind::File => Some(entry.clone()),
+                    _ => None,
+                };
+                let res = hidden_files::get_file_name(match &single {
+                    Some(entry) => {
+                        let extension = hidden_files::get_extension(&entry.name);
+                        FileDialog::new()
+                            .set_file_name(&entry.name)
+                            .add_filter(&extension, &[&extension])
+                            .save_file()
+                    }
+                    None => FileDialog::new().pick_folder(),
+                });
 
                 let handle_clone1 = handle_clone.clone();
                 let (output_file_name, _) = res.clone().unwrap_or((String::new(), String::new()));
@@ -235,7 +409,7 @@ fn extract_file(handle_weak: &Weak<App>) {
                     let handle = handle_clone1.unwrap();
                     // 选择文件完成后，就要设置非模式状态，以便处理取消操作
                     handle.set_waitting(false);
-                    if output_file_name.len() > 0 {
+                    if !output_file_name.is_empty() {
                         handle.set_output_file(SharedString::from(output_file_name));
                         handle.set_current_progress(0);
                         handle.set_show_progress(true);
@@ -244,38 +418,31 @@ fn extract_file(handle_weak: &Weak<App>) {
 
                 // 提取文件
                 if let Some((_, output_file_path)) = res {
-                    let handle_clone2 = handle_clone.clone();
-                    // 在UI线程访问这个变量
-                    let is_cancled = Arc::new(RwLock::new(false));
-                    let ui_is_cancled = is_cancled.clone();
+                    let progress = ui_progress(handle_clone.clone());
                     let mut copy_success = true;
-
-                    let copy_res = utils::extract_file(
-                        &first_file.path,
-                        &output_file_path,
-                        start_offset,
-                        end_offset,
-                        move |progress| {
-                            let handle_copy = handle_clone2.clone();
-                            let ui_is_cancled_copy = ui_is_cancled.clone();
-                            //通知UI线程当前进度
-                            let _ = slint::invoke_from_event_loop(move || {
-                                let handle = handle_copy.unwrap();
-                                handle.set_current_progress(progress);
-                                //是否取消了当前操作
-                                if let (true, Ok(mut ui_is_cancled)) =
-                                    (handle.get_user_canceled(), ui_is_cancled_copy.write())
-                                {
-                                    *ui_is_cancled = true;
-                                }
-                            });
-                        },
-                        is_cancled,
-                    );
+                    let copy_res = match single {
+                        Some(entry) => hidden_files::extract(
+                            Path::new(&first_file.path),
+                            &attachments,
+                            &entry,
+                            Path::new(&output_file_path),
+                            &options,
+                            &progress,
+                            &cancel,
+                        ),
+                        None => hidden_files::extract_all(
+                            Path::new(&first_file.path),
+                            &attachments,
+                            Path::new(&output_file_path),
+                            &options,
+                            &progress,
+                            &cancel,
+                        ),
+                    };
 
                     let msg = if let Err(err) = copy_res {
                         copy_success = false;
-                        format!("{:?}", err)
+                        error_message(&err)
                     } else {
                         "文件提取成功！".to_string()
                     };
@@ -290,7 +457,8 @@ fn extract_file(handle_weak: &Weak<App>) {
                         if copy_success {
                             //复制成功，清空文件
                             handle.set_first_file(slint_generatedApp::FileSpec::default());
-                            handle.set_second_file(slint_generatedApp::FileSpec::default());
+                            handle.set_attachments(ModelRc::default());
+                            handle.set_has_attachment(false);
                         }
                         alert(&handle, &msg, |_| {});
                     });
@@ -300,6 +468,206 @@ fn extract_file(handle_weak: &Weak<App>) {
     });
 }
 
+/// 校验附件，不写入任何文件
+fn verify_file(handle_weak: &Weak<App>) {
+    let handle = handle_weak.unwrap();
+    if handle.get_first_file().name.is_empty() || handle.get_waitting() {
+        return;
+    }
+
+    let first_file = hidden_files::FileSpec::from(&handle.get_first_file());
+    let password = get_password(&handle);
+
+    //读取文件信息
+    let attachments = match hidden_files::inspect(
+        Path::new(&first_file.path),
+        password.as_deref(),
+        &NoProgress,
+        &CancelToken::new(),
+    ) {
+        Ok(Some(attachments)) if !attachments.entries.is_empty() => attachments,
+        Ok(Some(attachments)) if attachments.sealed => {
+            alert(&handle, "附件信息已加密，请输入密码！", |_| {});
+            return;
+        }
+        Ok(_) => {
+            alert(&handle, "没有附件！", |_| {});
+            return;
+        }
+        Err(err) => {
+            alert(&handle, &error_message(&err), |_| {});
+            return;
+        }
+    };
+
+    handle.set_output_file(SharedString::from(first_file.name.as_str()));
+    handle.set_progress_title(SharedString::from("正在校验"));
+    handle.set_current_progress(0);
+    handle.set_show_progress(true);
+
+    let handle_clone = handle_weak.clone();
+    let cancel = new_job();
+    std::thread::spawn(move || {
+        let progress = ui_progress(handle_clone.clone());
+
+        let msg = match hidden_files::verify(
+            Path::new(&first_file.path),
+            &attachments,
+            &progress,
+            &cancel,
+        ) {
+            Ok(results) => results
+                .iter()
+                .map(|(name, status)| {
+                    let status = match status {
+                        hidden_files::VerifyStatus::Ok => "完整",
+                        hidden_files::VerifyStatus::Unchecked => "无校验值",
+                        hidden_files::VerifyStatus::Corrupted => "已损坏",
+                    };
+                    format!("{}:{}", name, status)
+                })
+                .collect::<Vec<_>>()
+                .join("\n"),
+            Err(err) => error_message(&err),
+        };
+
+        let _ = slint::invoke_from_event_loop(move || {
+            let handle = handle_clone.unwrap();
+            handle.set_current_progress(0);
+            handle.set_show_progress(false);
+            handle.set_user_canceled(false);
+            handle.set_progress_title(SharedString::from("正在保存"));
+            alert(&handle, &msg, |_| {});
+        });
+    });
+}
+
+/// 移除附件，还原源文件
+fn strip_file(handle_weak: &Weak<App>) {
+    let handle = handle_weak.unwrap();
+    if handle.get_first_file().name.is_empty() || handle.get_waitting() {
+        return;
+    }
+
+    let first_file = hidden_files::FileSpec::from(&handle.get_first_file());
+    let password = get_password(&handle);
+
+    //读取文件信息
+    let attachments = match hidden_files::inspect(
+        Path::new(&first_file.path),
+        password.as_deref(),
+        &NoProgress,
+        &CancelToken::new(),
+    ) {
+        Ok(Some(attachments)) if attachments.sealed => {
+            alert(&handle, "附件信息已加密，请输入密码！", |_| {});
+            return;
+        }
+        Ok(Some(attachments)) => attachments,
+        Ok(None) => {
+            alert(&handle, "没有附件！", |_| {});
+            return;
+        }
+        Err(err) => {
+            alert(&handle, &error_message(&err), |_| {});
+            return;
+        }
+    };
+    let handle_clone = handle_weak.clone();
+    let msg = format!(
+        "共{}个附件 还原后大小:{} 确定移除附件吗？",
+        attachments.entries.len(),
+        hidden_files::get_size_str(attachments.carrier_len())
+    );
+    confirm(&handle, &msg, move |confirm| {
+        let handle_clone = handle_clone.clone();
+        let attachments = attachments.clone();
+        let first_file = first_file.clone();
+        if confirm {
+            let cancel = new_job();
+            std::thread::spawn(move || {
+                set_waitting_from_thread(&handle_clone, true);
+                // 保存到源文件时直接截断源文件
+                let res = hidden_files::get_file_name(
+                    FileDialog::new()
+                        .set_file_name(&first_file.name)
+                        .add_filter(&first_file.extension, &[&first_file.extension])
+                        .save_file(),
+                );
+
+                let handle_clone1 = handle_clone.clone();
+                let (output_file_name, _) = res.clone().unwrap_or((String::new(), String::new()));
+
+                let _ = slint::invoke_from_event_loop(move || {
+                    let handle = handle_clone1.unwrap();
+                    // 选择文件完成后，就要设置非模式状态，以便处理取消操作
+                    handle.set_waitting(false);
+                    if !output_file_name.is_empty() {
+                        handle.set_output_file(SharedString::from(output_file_name));
+                        handle.set_progress_title(SharedString::from("正在还原"));
+                        handle.set_current_progress(0);
+                        handle.set_show_progress(true);
+                    }
+                });
+
+                if let Some((_, output_file_path)) = res {
+                    let progress = ui_progress(handle_clone.clone());
+                    let strip_res = hidden_files::strip(
+                        Path::new(&first_file.path),
+                        &attachments,
+                        Some(Path::new(&output_file_path)),
+                        &progress,
+                        &cancel,
+                    );
+
+                    let (strip_success, msg) = match strip_res {
+                        Ok(()) => (true, "附件已移除！".to_string()),
+                        Err(err) => (false, error_message(&err)),
+                    };
+
+                    let handle_clone3 = handle_clone.clone();
+                    let _ = slint::invoke_from_event_loop(move || {
+                        let handle = handle_clone3.unwrap();
+                        handle.set_current_progress(0);
+                        handle.set_show_progress(false);
+                        handle.set_user_canceled(false);
+                        handle.set_progress_title(SharedString::from("正在保存"));
+                        if strip_success {
+                            //还原成功，清空文件
+                            handle.set_first_file(slint_generatedApp::FileSpec::default());
+                            handle.set_has_attachment(false);
+                        }
+                        alert(&handle, &msg, |_| {});
+                    });
+                }
+            });
+        }
+    });
+}
+
+/// 界面上显示的错误提示
+fn error_message(err: &hidden_files::Error) -> String {
+    match err {
+        hidden_files::Error::Cancelled => "操作已取消！".to_string(),
+        hidden_files::Error::WrongPassword => "密码错误或数据已被篡改，请检查密码！".to_string(),
+        hidden_files::Error::PasswordRequired => "附件已加密，请输入密码！".to_string(),
+        hidden_files::Error::SizeLimit { .. } => {
+            format!("{}\n文件可能是恶意构造的，已停止提取。", err)
+        }
+        _ => err.to_string(),
+    }
+}
+
+/// 输入的密码，没有输入时返回None
+fn get_password(handle: &App) -> Option<String> {
+    let password = handle.get_password();
+    if password.is_empty() {
+        None
+    } else {
+        Some(password.to_string())
+    }
+}
+
 fn confirm<F: Fn(bool) + 'static>(app: &App, msg: &str, callback: F) {
     app.invoke_confirm(SharedString::from(msg));
     app.on_dialog_confirm(callback);
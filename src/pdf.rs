@@ -0,0 +1,1377 @@
+use flate2::read::ZlibDecoder;
+use std::{
+    collections::HashMap,
+    io::{self, Read, SeekFrom, Write},
+    ops::Range,
+};
+
+use crate::{
+    error::{Error, Result},
+    format::ReadSeek,
+    layout::Segments,
+    progress::ChunkProgress,
+};
+
+pub(crate) const PDF_MAGIC: &[u8] = b"%PDF-";
+/// 增量更新开头的注释，用来确认嵌入文件流是本程序写入的
+const MARKER: &str = "\n%hiDn\n";
+/// startxref和%%EOF在文件末尾1024字节内
+const TAIL_LEN: u64 = 1024;
+/// 读取字典、交叉引用表等结构时每次读取的长度
+const WINDOW_LEN: u64 = 64 * 1024;
+/// 解压交叉引用流和对象流时允许的最大长度
+const MAX_DECODED_LEN: u64 = 64 * 1024 * 1024;
+/// 沿着Prev读取交叉引用段的最大次数，防止循环引用
+const MAX_SECTIONS: usize = 1024;
+/// 数组和字典嵌套的最大层数
+const MAX_DEPTH: usize = 64;
+/// 交叉引用表中每项固定20字节
+const ENTRY_LEN: usize = 20;
+/// 附件在阅读器附件列表中显示的文件名，保存出来的文件可以直接提取附件
+const FILE_NAME: &str = "hidden_files.bin";
+
+fn is_space(byte: u8) -> bool {
+    matches!(byte, b'\0' | b'\t' | b'\n' | b'\x0c' | b'\r' | b' ')
+}
+
+fn is_delimiter(byte: u8) -> bool {
+    matches!(
+        byte,
+        b'(' | b')' | b'<' | b'>' | b'[' | b']' | b'{' | b'}' | b'/' | b'%'
+    )
+}
+
+/// 跳过空白和注释
+fn skip_space(data: &[u8], mut pos: usize) -> usize {
+    while let Some(&byte) = data.get(pos) {
+        if byte == b'%' {
+            while data.get(pos).is_some_and(|&b| b != b'\r' && b != b'\n') {
+                pos += 1;
+            }
+        } else if is_space(byte) {
+            pos += 1;
+        } else {
+            break;
+        }
+    }
+    pos
+}
+
+/// 普通词(数字、关键字)的结束位置
+fn token_end(data: &[u8], mut pos: usize) -> usize {
+    while data
+        .get(pos)
+        .is_some_and(|&b| !is_space(b) && !is_delimiter(b))
+    {
+        pos += 1;
+    }
+    pos
+}
+
+/// 跳过空白后读取指定的关键字，返回关键字之后的位置
+fn keyword(data: &[u8], pos: usize, word: &[u8]) -> Option<usize> {
+    let pos = skip_space(data, pos);
+    (&data[pos..token_end(data, pos)] == word).then_some(pos + word.len())
+}
+
+/// 跳过空白后读取非负整数
+fn parse_int(data: &[u8], pos: usize) -> Option<(u64, usize)> {
+    let pos = skip_space(data, pos);
+    let end = token_end(data, pos);
+    let token = &data[pos..end];
+    if token.is_empty() || !token.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    std::str::from_utf8(token)
+        .ok()?
+        .parse()
+        .ok()
+        .map(|n| (n, end))
+}
+
+/// 读取`n g 关键字`，用于间接引用(R)和间接对象开头(obj)
+fn parse_id(data: &[u8], pos: usize, word: &[u8]) -> Option<((u64, u64), usize)> {
+    let (num, pos) = parse_int(data, pos)?;
+    let (gen, pos) = parse_int(data, pos)?;
+    let end = keyword(data, pos, word)?;
+    Some(((num, gen), end))
+}
+
+/// 跳过一个对象，返回结束位置，间接引用作为一个对象
+fn skip_object(data: &[u8], pos: usize, depth: usize) -> Option<usize> {
+    if depth > MAX_DEPTH {
+        return None;
+    }
+    let pos = skip_space(data, pos);
+    match *data.get(pos)? {
+        b'<' if data.get(pos + 1) == Some(&b'<') => {
+            parse_dict(data, pos, depth).map(|(_, end)| end)
+        }
+        b'<' => data[pos..]
+            .iter()
+            .position(|&b| b == b'>')
+            .map(|len| pos + len + 1),
+        b'(' => {
+            // 字符串中可以有成对的括号和转义字符
+            let mut level = 0;
+            let mut pos = pos;
+            loop {
+                match *data.get(pos)? {
+                    b'\\' => pos += 1,
+                    b'(' => level += 1,
+                    b')' => {
+                        level -= 1;
+                        if level == 0 {
+                            return Some(pos + 1);
+                        }
+                    }
+                    _ => {}
+                }
+                pos += 1;
+            }
+        }
+        b'[' => {
+            let mut pos = pos + 1;
+            loop {
+                pos = skip_space(data, pos);
+                if *data.get(pos)? == b']' {
+                    return Some(pos + 1);
+                }
+                pos = skip_object(data, pos, depth + 1)?;
+            }
+        }
+        b'/' => Some(token_end(data, pos + 1)),
+        byte if is_delimiter(byte) => None,
+        _ => Some(
+            parse_id(data, pos, b"R")
+                .map(|(_, end)| end)
+                .unwrap_or_else(|| token_end(data, pos)),
+        ),
+    }
+}
+
+/// 字典中的各项，键不包括开头的`/`，值为原始数据
+struct Dict<'a> {
+    entries: Vec<(&'a [u8], &'a [u8])>,
+}
+
+/// 读取字典，返回字典和结束位置
+fn parse_dict(data: &[u8], pos: usize, depth: usize) -> Option<(Dict<'_>, usize)> {
+    let mut pos = skip_space(data, pos);
+    if !data[pos..].starts_with(b"<<") {
+        return None;
+    }
+    pos += 2;
+    let mut entries = vec![];
+    loop {
+        pos = skip_space(data, pos);
+        if data[pos..].starts_with(b">>") {
+            return Some((Dict { entries }, pos + 2));
+        }
+        if data.get(pos) != Some(&b'/') {
+            return None;
+        }
+        let key_end = token_end(data, pos + 1);
+        let value_start = skip_space(data, key_end);
+        let value_end = skip_object(data, value_start, depth + 1)?;
+        entries.push((&data[pos + 1..key_end], &data[value_start..value_end]));
+        pos = value_end;
+    }
+}
+
+/// 读取整数数组
+fn parse_ints(data: &[u8]) -> Option<Vec<u64>> {
+    let mut pos = skip_space(data, 0);
+    if data.get(pos) != Some(&b'[') {
+        return None;
+    }
+    pos += 1;
+    let mut ints = vec![];
+    loop {
+        pos = skip_space(data, pos);
+        if *data.get(pos)? == b']' {
+            return Some(ints);
+        }
+        let (n, end) = parse_int(data, pos)?;
+        ints.push(n);
+        pos = end;
+    }
+}
+
+impl<'a> Dict<'a> {
+    fn get(&self, key: &[u8]) -> Option<&'a [u8]> {
+        self.entries
+            .iter()
+            .find(|(k, _)| *k == key)
+            .map(|(_, value)| *value)
+    }
+
+    fn get_int(&self, key: &[u8]) -> Option<u64> {
+        let value = self.get(key)?;
+        parse_int(value, 0)
+            .filter(|(_, end)| *end == value.len())
+            .map(|(n, _)| n)
+    }
+
+    fn get_ref(&self, key: &[u8]) -> Option<(u64, u64)> {
+        let value = self.get(key)?;
+        parse_id(value, 0, b"R")
+            .filter(|(_, end)| *end == value.len())
+            .map(|(id, _)| id)
+    }
+
+    /// 生成替换或者添加一项之后的字典
+    fn with(&self, key: &[u8], value: &[u8]) -> Vec<u8> {
+        let mut dict = b"<<".to_vec();
+        let others = self.entries.iter().filter(|(k, _)| *k != key);
+        for (k, v) in others.chain([(key, value)].iter()) {
+            dict.extend_from_slice(b" /");
+            dict.extend_from_slice(k);
+            dict.push(b' ');
+            dict.extend_from_slice(v);
+        }
+        dict.extend_from_slice(b" >>");
+        dict
+    }
+}
+
+/// 读取从`offset`开始最多`max`字节，超过文件长度的部分不读取
+fn read_at(reader: &mut dyn ReadSeek, len: u64, offset: u64, max: u64) -> io::Result<Vec<u8>> {
+    let mut data = vec![];
+    if offset < len {
+        reader.seek(SeekFrom::Start(offset))?;
+        Read::take(&mut *reader, max.min(len - offset)).read_to_end(&mut data)?;
+    }
+    Ok(data)
+}
+
+/// 读取文件末尾startxref之后的最后一个交叉引用段位置
+fn find_startxref(reader: &mut dyn ReadSeek, len: u64) -> io::Result<Option<u64>> {
+    let tail = read_at(reader, len, len.saturating_sub(TAIL_LEN), TAIL_LEN)?;
+    let keyword = b"startxref";
+    Ok(tail
+        .windows(keyword.len())
+        .rposition(|window| window == keyword)
+        .and_then(|pos| parse_int(&tail, pos + keyword.len()))
+        .map(|(offset, _)| offset)
+        .filter(|&offset| offset < len))
+}
+
+/// 交叉引用表中正在使用的一项
+#[derive(Clone, Copy)]
+enum Entry {
+    /// 对象在文件中的位置和代数
+    Offset(u64, u64),
+    /// 对象保存在对象流中，对象流的编号和对象在流中的序号
+    Compressed(u64, u64),
+}
+
+/// 流对象的字典、字典在读取的数据中的范围和解码后的数据
+type Stream<'a> = (Dict<'a>, Range<usize>, Vec<u8>);
+
+/// 需要替换的对象编号、代数和新的内容
+type Replacement = ((u64, u64), Vec<u8>);
+
+/// 一个交叉引用段
+struct Section {
+    entries: HashMap<u64, Entry>,
+    /// 尾部字典，交叉引用流时为流的字典
+    trailer: Vec<u8>,
+    /// 是否为交叉引用流
+    stream: bool,
+}
+
+/// 读取`offset`处的交叉引用表或交叉引用流，结构错误时返回None
+fn read_section(reader: &mut dyn ReadSeek, len: u64, offset: u64) -> io::Result<Option<Section>> {
+    let head = read_at(reader, len, offset, WINDOW_LEN)?;
+    match keyword(&head, 0, b"xref") {
+        Some(pos) => read_table(reader, len, offset + pos as u64),
+        None => read_xref_stream(reader, len, offset, &head),
+    }
+}
+
+/// 读取交叉引用表(xref之后的部分)和尾部字典
+fn read_table(reader: &mut dyn ReadSeek, len: u64, offset: u64) -> io::Result<Option<Section>> {
+    let mut base = offset;
+    let mut data = read_at(reader, len, base, WINDOW_LEN)?;
+    let mut pos = 0;
+    let mut entries = HashMap::new();
+    loop {
+        // 剩下的数据不够解析子段开头或者尾部字典时，从当前位置重新读取
+        if data.len() - pos < WINDOW_LEN as usize / 2 && base + (data.len() as u64) < len {
+            base += pos as u64;
+            data = read_at(reader, len, base, WINDOW_LEN)?;
+            pos = 0;
+        }
+        if let Some(end) = keyword(&data, pos, b"trailer") {
+            let start = skip_space(&data, end);
+            return Ok(parse_dict(&data, start, 0).map(|(_, end)| Section {
+                entries,
+                trailer: data[start..end].to_vec(),
+                stream: false,
+            }));
+        }
+        let (first, count) = match parse_int(&data, pos).and_then(|(first, end)| {
+            parse_int(&data, end).map(|(count, end)| ((first, count), end))
+        }) {
+            Some((header, end)) => {
+                pos = skip_space(&data, end);
+                header
+            }
+            None => return Ok(None),
+        };
+        for num in first..first.saturating_add(count) {
+            if data.len() - pos < ENTRY_LEN {
+                base += pos as u64;
+                data = read_at(reader, len, base, WINDOW_LEN)?;
+                pos = 0;
+                if data.len() < ENTRY_LEN {
+                    return Ok(None);
+                }
+            }
+            let entry = &data[pos..pos + ENTRY_LEN];
+            pos += ENTRY_LEN;
+            let offset = parse_int(&entry[..10], 0).map(|(offset, _)| offset);
+            let gen = parse_int(&entry[11..16], 0).map(|(gen, _)| gen);
+            match (offset, gen, entry[17]) {
+                (Some(offset), Some(gen), b'n') => {
+                    entries.entry(num).or_insert(Entry::Offset(offset, gen));
+                }
+                (Some(_), Some(_), b'f') => {}
+                _ => return Ok(None),
+            }
+        }
+    }
+}
+
+/// 读取交叉引用流
+fn read_xref_stream(
+    reader: &mut dyn ReadSeek,
+    len: u64,
+    offset: u64,
+    head: &[u8],
+) -> io::Result<Option<Section>> {
+    let (dict, dict_range, data) = match read_stream(reader, len, offset, head, None)? {
+        Some(stream) => stream,
+        None => return Ok(None),
+    };
+    Ok(parse_xref_stream(&dict, &data).map(|entries| Section {
+        entries,
+        trailer: head[dict_range].to_vec(),
+        stream: true,
+    }))
+}
+
+/// 解析交叉引用流中的各项，每项的字段长度由W指定
+fn parse_xref_stream(dict: &Dict, data: &[u8]) -> Option<HashMap<u64, Entry>> {
+    if dict.get(b"Type") != Some(b"/XRef") {
+        return None;
+    }
+    let widths = parse_ints(dict.get(b"W")?)?;
+    if widths.len() != 3 || widths.iter().any(|&width| width > 8) {
+        return None;
+    }
+    let widths: Vec<usize> = widths.into_iter().map(|width| width as usize).collect();
+    let index = match dict.get(b"Index") {
+        Some(index) => parse_ints(index)?,
+        None => vec![0, dict.get_int(b"Size")?],
+    };
+    if index.len() % 2 != 0 {
+        return None;
+    }
+    let mut rows = data.chunks_exact(widths.iter().sum::<usize>().max(1));
+    let mut entries = HashMap::new();
+    for range in index.chunks(2) {
+        for num in range[0]..range[0].checked_add(range[1])? {
+            let mut row = rows.next()?;
+            let mut fields = [0u64; 3];
+            for (field, &width) in fields.iter_mut().zip(&widths) {
+                *field = row[..width]
+                    .iter()
+                    .fold(0, |value, &byte| value << 8 | byte as u64);
+                row = &row[width..];
+            }
+            // 类型字段长度为0时默认为1
+            let kind = if widths[0] == 0 { 1 } else { fields[0] };
+            match kind {
+                1 => entries.insert(num, Entry::Offset(fields[1], fields[2])),
+                2 => entries.insert(num, Entry::Compressed(fields[1], fields[2])),
+                _ => None,
+            };
+        }
+    }
+    Some(entries)
+}
+
+/// # 读取流对象
+///
+/// 返回流的字典、字典在`head`中的范围和解码后的数据，结构错误或者不支持的编码时返回None
+///
+/// 参数:
+/// * `offset`: 对象在文件中的位置
+/// * `head`: 从`offset`开始读取的数据，需要包含字典
+/// * `entries`: 用来查找间接引用的长度，交叉引用流的长度必须是直接对象
+fn read_stream<'a>(
+    reader: &mut dyn ReadSeek,
+    len: u64,
+    offset: u64,
+    head: &'a [u8],
+    entries: Option<&HashMap<u64, Entry>>,
+) -> io::Result<Option<Stream<'a>>> {
+    let (dict, dict_start, dict_end) = match parse_id(head, 0, b"obj") {
+        Some((_, pos)) => {
+            let start = skip_space(head, pos);
+            match parse_dict(head, start, 0) {
+                Some((dict, end)) => (dict, start, end),
+                None => return Ok(None),
+            }
+        }
+        None => return Ok(None),
+    };
+    let stream_len = match (dict.get_int(b"Length"), dict.get_ref(b"Length"), entries) {
+        (Some(stream_len), _, _) => Some(stream_len),
+        (None, Some((num, _)), Some(entries)) => read_int(reader, len, entries, num)?,
+        _ => None,
+    };
+    let start = stream_start(head, dict_end).map(|pos| offset + pos as u64);
+    let (start, stream_len) = match (start, stream_len) {
+        (Some(start), Some(stream_len))
+            if start.checked_add(stream_len).is_some_and(|end| end <= len)
+                && stream_len <= MAX_DECODED_LEN =>
+        {
+            (start, stream_len)
+        }
+        _ => return Ok(None),
+    };
+    let raw = read_at(reader, len, start, stream_len)?;
+    Ok(decode(raw, &dict).map(|data| (dict, dict_start..dict_end, data)))
+}
+
+/// 流数据的开始位置，stream之后是CRLF或者LF
+fn stream_start(data: &[u8], dict_end: usize) -> Option<usize> {
+    let pos = keyword(data, dict_end, b"stream")?;
+    match data.get(pos..pos + 2)? {
+        b"\r\n" => Some(pos + 2),
+        [b'\n', _] => Some(pos + 1),
+        _ => None,
+    }
+}
+
+/// 读取`num`号对象中的整数，用于间接引用的流长度
+fn read_int(
+    reader: &mut dyn ReadSeek,
+    len: u64,
+    entries: &HashMap<u64, Entry>,
+    num: u64,
+) -> io::Result<Option<u64>> {
+    Ok(match entries.get(&num) {
+        Some(&Entry::Offset(offset, _)) => {
+            let data = read_at(reader, len, offset, WINDOW_LEN)?;
+            parse_id(&data, 0, b"obj")
+                .filter(|((n, _), _)| *n == num)
+                .and_then(|(_, pos)| parse_int(&data, pos))
+                .map(|(n, _)| n)
+        }
+        _ => None,
+    })
+}
+
+/// 按照Filter和DecodeParms解码流数据，只支持FlateDecode和PNG预测
+fn decode(raw: Vec<u8>, dict: &Dict) -> Option<Vec<u8>> {
+    // 只有一个编码时可以是名称或者只有一项的数组
+    let filter = dict.get(b"Filter").map(|filter| {
+        match filter
+            .strip_prefix(b"[")
+            .and_then(|filter| filter.strip_suffix(b"]"))
+        {
+            Some(filters) => {
+                let start = skip_space(filters, 0);
+                let end = skip_object(filters, start, 0).unwrap_or(start);
+                if skip_space(filters, end) == filters.len() {
+                    &filters[start..end]
+                } else {
+                    filters
+                }
+            }
+            None => filter,
+        }
+    });
+    let data = match filter {
+        None => raw,
+        Some(b"/FlateDecode") => {
+            let mut data = vec![];
+            ZlibDecoder::new(&raw[..])
+                .take(MAX_DECODED_LEN)
+                .read_to_end(&mut data)
+                .ok()?;
+            data
+        }
+        Some(_) => return None,
+    };
+    let params = match dict.get(b"DecodeParms") {
+        Some(params) => Some(parse_dict(params, 0, 0)?.0),
+        None => None,
+    };
+    let param = |key: &[u8], default| {
+        params
+            .as_ref()
+            .and_then(|params| params.get(key))
+            .map_or(Some(default), |_| params.as_ref()?.get_int(key))
+    };
+    match param(b"Predictor", 1)? {
+        1 => Some(data),
+        10..=15 if param(b"Colors", 1)? == 1 && param(b"BitsPerComponent", 8)? == 8 => {
+            png_unpredict(&data, usize::try_from(param(b"Columns", 1)?).ok()?)
+        }
+        _ => None,
+    }
+}
+
+/// 还原PNG预测，每行开头是预测方式。Columns来自文件，超过数据长度时不分配内存直接返回None
+fn png_unpredict(data: &[u8], columns: usize) -> Option<Vec<u8>> {
+    let row_len = columns.checked_add(1)?;
+    if columns == 0 || row_len > data.len() {
+        return None;
+    }
+    let mut output = Vec::with_capacity(data.len());
+    let mut prev = vec![0u8; columns];
+    for row in data.chunks(row_len) {
+        if row.len() != row_len {
+            return None;
+        }
+        let mut current = vec![0u8; columns];
+        for i in 0..columns {
+            let left = if i > 0 { current[i - 1] } else { 0 };
+            let up = prev[i];
+            let up_left = if i > 0 { prev[i - 1] } else { 0 };
+            let predicted = match row[0] {
+                0 => 0,
+                1 => left,
+                2 => up,
+                3 => ((left as u16 + up as u16) / 2) as u8,
+                4 => {
+                    let p = left as i16 + up as i16 - up_left as i16;
+                    let (pa, pb, pc) = (
+                        (p - left as i16).abs(),
+                        (p - up as i16).abs(),
+                        (p - up_left as i16).abs(),
+                    );
+                    if pa <= pb && pa <= pc {
+                        left
+                    } else if pb <= pc {
+                        up
+                    } else {
+                        up_left
+                    }
+                }
+                _ => return None,
+            };
+            current[i] = row[i + 1].wrapping_add(predicted);
+        }
+        output.extend_from_slice(&current);
+        prev = current;
+    }
+    Some(output)
+}
+
+/// 合并后的交叉引用表
+struct Xref {
+    /// 每个对象最新的位置
+    entries: HashMap<u64, Entry>,
+    /// 最后一个交叉引用段的尾部字典
+    trailer: Vec<u8>,
+    /// 最后一个交叉引用段是否为交叉引用流
+    stream: bool,
+}
+
+/// 从最后一个交叉引用段开始沿着Prev读取全部交叉引用段，新的段优先
+fn read_xref(
+    reader: &mut dyn ReadSeek,
+    len: u64,
+    offset: u64,
+    chunks: &mut ChunkProgress,
+) -> Result<Option<Xref>> {
+    let mut xref: Option<Xref> = None;
+    let mut pending = vec![offset];
+    let mut visited = vec![];
+    while let Some(offset) = pending.pop() {
+        if visited.contains(&offset) || visited.len() >= MAX_SECTIONS {
+            continue;
+        }
+        visited.push(offset);
+        chunks.scan(offset)?;
+        // 更早的段损坏时忽略，大多数阅读器也能打开
+        let section = match read_section(reader, len, offset)? {
+            Some(section) => section,
+            None if xref.is_none() => return Ok(None),
+            None => continue,
+        };
+        if let Some((trailer, _)) = parse_dict(&section.trailer, 0, 0) {
+            // 混合格式的文件中，XRefStm指向的交叉引用流优先于Prev
+            let valid = |offset: &u64| *offset < len;
+            pending.extend(trailer.get_int(b"Prev").filter(valid));
+            pending.extend(trailer.get_int(b"XRefStm").filter(valid));
+        }
+        let xref = xref.get_or_insert_with(|| Xref {
+            entries: HashMap::new(),
+            trailer: section.trailer.clone(),
+            stream: section.stream,
+        });
+        for (num, entry) in section.entries {
+            xref.entries.entry(num).or_insert(entry);
+        }
+    }
+    Ok(xref)
+}
+
+/// 读取`num`号对象，返回代数和对象内容(obj之后的数据)，不存在或者结构错误时返回None
+fn read_object(
+    reader: &mut dyn ReadSeek,
+    len: u64,
+    entries: &HashMap<u64, Entry>,
+    num: u64,
+) -> io::Result<Option<(u64, Vec<u8>)>> {
+    match entries.get(&num) {
+        Some(&Entry::Offset(offset, gen)) => {
+            let data = read_at(reader, len, offset, WINDOW_LEN)?;
+            Ok(parse_id(&data, 0, b"obj")
+                .filter(|(id, _)| *id == (num, gen))
+                .map(|(_, pos)| (gen, data[pos..].to_vec())))
+        }
+        Some(&Entry::Compressed(stream, index)) => {
+            let offset = match entries.get(&stream) {
+                Some(&Entry::Offset(offset, _)) => offset,
+                _ => return Ok(None),
+            };
+            let head = read_at(reader, len, offset, WINDOW_LEN)?;
+            let (dict, _, data) = match read_stream(reader, len, offset, &head, Some(entries))? {
+                Some(stream) => stream,
+                None => return Ok(None),
+            };
+            Ok(compressed_object(&dict, &data, num, index).map(|object| (0, object.to_vec())))
+        }
+        None => Ok(None),
+    }
+}
+
+/// 在对象流中查找对象，流开头是每个对象的编号和相对First的位置
+fn compressed_object<'a>(dict: &Dict, data: &'a [u8], num: u64, index: u64) -> Option<&'a [u8]> {
+    if dict.get(b"Type") != Some(b"/ObjStm") || index >= dict.get_int(b"N")? {
+        return None;
+    }
+    let first = dict.get_int(b"First")? as usize;
+    let mut pos = 0;
+    for _ in 0..index {
+        pos = parse_int(data, parse_int(data, pos)?.1)?.1;
+    }
+    let (n, pos) = parse_int(data, pos)?;
+    let (offset, _) = parse_int(data, pos)?;
+    let start = first.checked_add(offset as usize)?;
+    (n == num && start <= data.len()).then(|| &data[start..])
+}
+
+/// # 生成加入附件之后的目录或者名称字典
+///
+/// 返回需要替换的对象编号、代数和新的字典。已经有EmbeddedFiles名称树时需要按名称排序合并，
+/// 不支持，返回错误。文件说明不在名称树中时，其他程序重新保存PDF会丢弃附件
+fn names_object(
+    reader: &mut dyn ReadSeek,
+    len: u64,
+    entries: &HashMap<u64, Entry>,
+    root: u64,
+    filespec: u64,
+) -> Result<Replacement> {
+    let unsupported = || Error::InvalidInput("不支持这个PDF文件的目录结构！".to_string());
+    let tree = format!("<< /Names [({}) {} 0 R] >>", FILE_NAME, filespec);
+    let (gen, data) = read_object(reader, len, entries, root)?.ok_or_else(unsupported)?;
+    let catalog = match parse_dict(&data, 0, 0) {
+        Some((catalog, _)) if catalog.get(b"Type") == Some(b"/Catalog") => catalog,
+        _ => return Err(unsupported()),
+    };
+    let names = match catalog.get(b"Names") {
+        None => {
+            let names = format!("<< /EmbeddedFiles {} >>", tree);
+            return Ok(((root, gen), catalog.with(b"Names", names.as_bytes())));
+        }
+        Some(names) => names,
+    };
+    let names_data;
+    let (id, names) = match (parse_dict(names, 0, 0), catalog.get_ref(b"Names")) {
+        (Some((names, _)), _) => (None, names),
+        (None, Some((num, _))) => {
+            let (gen, data) = read_object(reader, len, entries, num)?.ok_or_else(unsupported)?;
+            names_data = data;
+            let (names, _) = parse_dict(&names_data, 0, 0).ok_or_else(unsupported)?;
+            (Some((num, gen)), names)
+        }
+        _ => return Err(unsupported()),
+    };
+    if names.get(b"EmbeddedFiles").is_some() {
+        return Err(Error::InvalidInput(
+            "PDF中已经有嵌入文件，不支持保存到文件内部！".to_string(),
+        ));
+    }
+    let names = names.with(b"EmbeddedFiles", tree.as_bytes());
+    Ok(match id {
+        // 名称字典是间接对象时只替换名称字典
+        Some(id) => (id, names),
+        None => ((root, gen), catalog.with(b"Names", &names)),
+    })
+}
+
+/// # PDF增量更新
+///
+/// 附件、头部和尾部保存为一个嵌入文件流，之后写入文件说明、新的交叉引用段和尾部字典，
+/// 原始源文件不变。文件说明加入目录的EmbeddedFiles名称树，
+/// 其他程序重新保存PDF时不会丢弃附件对象
+pub(crate) struct Update {
+    /// 原始源文件长度，增量更新从这里开始
+    carrier_len: u64,
+    /// 上一个交叉引用段的位置
+    prev: u64,
+    /// 上一个交叉引用段是否为交叉引用流，新的交叉引用段使用相同的格式
+    xref_stream: bool,
+    /// 第一个新对象的编号，依次为嵌入文件流、流长度、文件说明、交叉引用流
+    first: u64,
+    /// 从上一个尾部字典复制的项
+    trailer: Vec<u8>,
+    /// 需要替换的目录或者名称字典
+    names: Replacement,
+}
+
+impl Update {
+    /// 读取源文件的交叉引用表和目录，源文件不是PDF、已加密或者已经有嵌入文件时返回错误
+    pub fn read(reader: &mut dyn ReadSeek, len: u64, chunks: &mut ChunkProgress) -> Result<Self> {
+        let invalid = || Error::InvalidInput("源文件不是有效的PDF文件！".to_string());
+        if read_at(reader, len, 0, PDF_MAGIC.len() as u64)? != PDF_MAGIC {
+            return Err(invalid());
+        }
+        let prev = find_startxref(reader, len)?.ok_or_else(invalid)?;
+        let xref = read_xref(reader, len, prev, chunks)?.ok_or_else(invalid)?;
+        let (trailer, _) = parse_dict(&xref.trailer, 0, 0).ok_or_else(invalid)?;
+        if trailer.get(b"Encrypt").is_some() {
+            return Err(Error::InvalidInput("不支持加密的PDF文件！".to_string()));
+        }
+        let first = trailer
+            .get_int(b"Size")
+            .filter(|&size| size < u32::MAX as u64)
+            .ok_or_else(invalid)?;
+        let (root, _) = trailer.get_ref(b"Root").ok_or_else(invalid)?;
+        let mut copied = vec![];
+        for key in [&b"Root"[..], b"Info", b"ID"] {
+            if let Some(value) = trailer.get(key) {
+                copied.extend_from_slice(b" /");
+                copied.extend_from_slice(key);
+                copied.push(b' ');
+                copied.extend_from_slice(value);
+            }
+        }
+        let names = names_object(reader, len, &xref.entries, root, first + 2)?;
+        log::debug!(
+            "PDF交叉引用段:{} 交叉引用流:{} 新对象:{} 修改对象:{:?}",
+            prev,
+            xref.stream,
+            first,
+            names.0
+        );
+        Ok(Self {
+            carrier_len: len,
+            prev,
+            xref_stream: xref.stream,
+            first,
+            trailer: copied,
+            names,
+        })
+    }
+
+    /// 增量更新开头到嵌入文件流数据之前的部分，流长度在数据之后写入
+    fn start(&self) -> String {
+        format!(
+            "{}{} 0 obj\n<< /Type /EmbeddedFile /Length {} 0 R >>\nstream\n",
+            MARKER,
+            self.first,
+            self.first + 1
+        )
+    }
+
+    /// 写入嵌入文件流数据之前的部分
+    pub fn write_start(&self, output: &mut dyn Write) -> io::Result<()> {
+        output.write_all(self.start().as_bytes())
+    }
+
+    /// 写入嵌入文件流之后的部分，`data_len`为附件、头部和尾部的总长度
+    pub fn write_end(&self, output: &mut dyn Write, data_len: u64) -> io::Result<()> {
+        let base = self.carrier_len + self.start().len() as u64 + data_len;
+        let mut buf = b"\nendstream\nendobj\n".to_vec();
+        let mut objects = vec![((self.first, 0), self.carrier_len + MARKER.len() as u64)];
+        let mut add = |buf: &mut Vec<u8>, id: (u64, u64), body: &[u8]| {
+            objects.push((id, base + buf.len() as u64));
+            buf.extend_from_slice(format!("{} {} obj\n", id.0, id.1).as_bytes());
+            buf.extend_from_slice(body);
+            buf.extend_from_slice(b"\nendobj\n");
+        };
+        add(
+            &mut buf,
+            (self.first + 1, 0),
+            data_len.to_string().as_bytes(),
+        );
+        let filespec = format!(
+            "<< /Type /Filespec /F ({0}) /UF ({0}) /EF << /F {1} 0 R >> >>",
+            FILE_NAME, self.first
+        );
+        add(&mut buf, (self.first + 2, 0), filespec.as_bytes());
+        let (id, dict) = &self.names;
+        add(&mut buf, *id, dict);
+
+        let xref_offset = base + buf.len() as u64;
+        let trailer = String::from_utf8_lossy(&self.trailer);
+        if self.xref_stream {
+            // 交叉引用流包括自身，不压缩
+            let num = self.first + 3;
+            objects.push(((num, 0), xref_offset));
+            objects.sort();
+            let mut rows = vec![];
+            for ((_, gen), offset) in &objects {
+                rows.push(1);
+                rows.extend_from_slice(&offset.to_be_bytes());
+                rows.extend_from_slice(&(*gen as u16).to_be_bytes());
+            }
+            let index: Vec<String> = subsections(&objects)
+                .iter()
+                .map(|(first, count)| format!("{} {}", first, count))
+                .collect();
+            buf.extend_from_slice(
+                format!(
+                    "{} 0 obj\n<< /Type /XRef /Size {} /Index [{}] /W [1 8 2] /Length {}{} /Prev {} >>\nstream\n",
+                    num,
+                    num + 1,
+                    index.join(" "),
+                    rows.len(),
+                    trailer,
+                    self.prev
+                )
+                .as_bytes(),
+            );
+            buf.extend_from_slice(&rows);
+            buf.extend_from_slice(b"\nendstream\nendobj\n");
+        } else {
+            objects.sort();
+            buf.extend_from_slice(b"xref\n");
+            let mut rest = &objects[..];
+            for (first, count) in subsections(&objects) {
+                buf.extend_from_slice(format!("{} {}\n", first, count).as_bytes());
+                for ((_, gen), offset) in &rest[..count as usize] {
+                    buf.extend_from_slice(format!("{:010} {:05} n\r\n", offset, gen).as_bytes());
+                }
+                rest = &rest[count as usize..];
+            }
+            buf.extend_from_slice(
+                format!(
+                    "trailer\n<< /Size {}{} /Prev {} >>\n",
+                    self.first + 3,
+                    trailer,
+                    self.prev
+                )
+                .as_bytes(),
+            );
+        }
+        buf.extend_from_slice(format!("startxref\n{}\n%%EOF\n", xref_offset).as_bytes());
+        output.write_all(&buf)
+    }
+}
+
+/// 把排序后的对象分成编号连续的子段，返回每段第一个编号和对象数
+fn subsections(objects: &[((u64, u64), u64)]) -> Vec<(u64, u64)> {
+    let mut subsections: Vec<(u64, u64)> = vec![];
+    for ((num, _), _) in objects {
+        match subsections.last_mut() {
+            Some((first, count)) if *first + *count == *num => *count += 1,
+            _ => subsections.push((*num, 1)),
+        }
+    }
+    subsections
+}
+
+/// # 查找保存附件的嵌入文件流
+///
+/// 只识别本程序写入的增量更新，即交叉引用段中位置最前的对象之前有标记注释。
+/// 附件之后其他程序可能又追加了增量更新(注释、填写表单、签名等)，所以沿着Prev向前查找每个交叉引用段。
+/// 返回增量更新之前的原始源文件和流数据，之后追加的增量更新不属于原始源文件。没有时返回None
+pub(crate) fn hidden_segments(
+    reader: &mut dyn ReadSeek,
+    len: u64,
+    chunks: &mut ChunkProgress,
+) -> Result<Option<(Segments, Segments)>> {
+    Ok(find_hidden(reader, len, chunks)?.map(|(_, carrier, data)| (carrier, data)))
+}
+
+/// # 检查保存附件之后PDF是否又被修改过
+///
+/// 附件所在的增量更新不在文件末尾时返回true，之后是其他程序追加的增量更新(注释、签名等)，
+/// 移除或者替换附件时还原为保存附件之前的PDF会丢失这些修改
+pub(crate) fn modified_after(
+    reader: &mut dyn ReadSeek,
+    len: u64,
+    chunks: &mut ChunkProgress,
+) -> Result<bool> {
+    let offset = match find_hidden(reader, len, chunks)? {
+        Some((offset, _, _)) => offset,
+        None => return Ok(false),
+    };
+    // 与Update::write_end写入的结尾相同
+    let end = format!("startxref\n{}\n%%EOF\n", offset);
+    let tail = read_at(
+        reader,
+        len,
+        len.saturating_sub(end.len() as u64),
+        end.len() as u64,
+    )?;
+    Ok(tail != end.as_bytes())
+}
+
+/// 沿着Prev查找附件所在的交叉引用段，返回交叉引用段的位置、原始源文件和流数据
+fn find_hidden(
+    reader: &mut dyn ReadSeek,
+    len: u64,
+    chunks: &mut ChunkProgress,
+) -> Result<Option<(u64, Segments, Segments)>> {
+    if read_at(reader, len, 0, PDF_MAGIC.len() as u64)? != PDF_MAGIC {
+        return Ok(None);
+    }
+    let mut next = find_startxref(reader, len)?;
+    let mut visited = vec![];
+    while let Some(offset) = next {
+        if visited.len() >= MAX_SECTIONS {
+            break;
+        }
+        visited.push(offset);
+        // 从文件末尾向前查找，进度按照已经检查过的长度计算
+        chunks.scan(len - offset)?;
+        let section = match read_section(reader, len, offset)? {
+            Some(section) => section,
+            None => break,
+        };
+        if let Some((carrier, data)) = hidden_stream(reader, len, offset, &section)? {
+            return Ok(Some((offset, carrier, data)));
+        }
+        // Prev超出文件或者形成循环时停止查找
+        next = parse_dict(&section.trailer, 0, 0)
+            .and_then(|(trailer, _)| trailer.get_int(b"Prev"))
+            .filter(|prev| *prev < len && !visited.contains(prev));
+    }
+    Ok(None)
+}
+
+/// 检查`offset`处的交叉引用段是否为本程序写入的增量更新，是时返回原始源文件和流数据
+fn hidden_stream(
+    reader: &mut dyn ReadSeek,
+    len: u64,
+    offset: u64,
+    section: &Section,
+) -> Result<Option<(Segments, Segments)>> {
+    let start = section
+        .entries
+        .values()
+        .filter_map(|entry| match entry {
+            Entry::Offset(offset, _) => Some(*offset),
+            Entry::Compressed(..) => None,
+        })
+        .min();
+    let (carrier_len, start) =
+        match start.and_then(|start| Some((start.checked_sub(MARKER.len() as u64)?, start))) {
+            Some(position) => position,
+            None => return Ok(None),
+        };
+    if read_at(reader, len, carrier_len, MARKER.len() as u64)? != MARKER.as_bytes() {
+        return Ok(None);
+    }
+
+    let corrupt = || Error::CorruptTrailer("PDF中的附件对象结构错误！".to_string());
+    let head = read_at(reader, len, start, WINDOW_LEN)?;
+    let (dict, dict_end) = parse_id(&head, 0, b"obj")
+        .and_then(|(_, pos)| parse_dict(&head, pos, 0))
+        .filter(|(dict, _)| dict.get(b"Type") == Some(b"/EmbeddedFile"))
+        .ok_or_else(corrupt)?;
+    let data_len = match (dict.get_int(b"Length"), dict.get_ref(b"Length")) {
+        (Some(data_len), _) => Some(data_len),
+        (None, Some((num, _))) => read_int(reader, len, &section.entries, num)?,
+        _ => None,
+    };
+    let data_start = stream_start(&head, dict_end).map(|pos| start + pos as u64);
+    let (data_start, data_len) = match (data_start, data_len) {
+        (Some(data_start), Some(data_len))
+            if data_start
+                .checked_add(data_len)
+                .is_some_and(|end| end <= offset) =>
+        {
+            (data_start, data_len)
+        }
+        _ => return Err(corrupt()),
+    };
+    let mut carrier = vec![(0, carrier_len)];
+    let mut data = vec![(data_start, data_len)];
+    carrier.retain(|(_, len)| *len > 0);
+    data.retain(|(_, len)| *len > 0);
+    Ok(Some((carrier, data)))
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2::{write::ZlibEncoder, Compression};
+    use std::{fs, io::Cursor};
+
+    use super::*;
+    use crate::{
+        layout::{
+            tests::{inspect, round_trip},
+            Layout,
+        },
+        CancelToken, EmbedOptions, ExtractOptions, Extractor, NoProgress,
+    };
+
+    const PAGES: &str = "<< /Type /Pages /Kids [3 0 R] /Count 1 >>";
+    const PAGE: &str = "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 200 200] >>";
+
+    fn object(pdf: &mut Vec<u8>, num: u64, body: &[u8]) -> u64 {
+        let offset = pdf.len() as u64;
+        pdf.extend(format!("{} 0 obj\n", num).bytes());
+        pdf.extend_from_slice(body);
+        pdf.extend_from_slice(b"\nendobj\n");
+        offset
+    }
+
+    /// 对象编号从1开始连续，使用交叉引用表，`trailer`加入尾部字典
+    fn classic(objects: &[&str], trailer: &str) -> Vec<u8> {
+        let mut pdf = b"%PDF-1.4\n%\xe2\xe3\xcf\xd3\n".to_vec();
+        let offsets: Vec<u64> = (1..)
+            .zip(objects)
+            .map(|(num, body)| object(&mut pdf, num, body.as_bytes()))
+            .collect();
+        let xref = pdf.len();
+        pdf.extend(format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1).bytes());
+        for offset in offsets {
+            pdf.extend(format!("{:010} 00000 n \n", offset).bytes());
+        }
+        pdf.extend(
+            format!(
+                "trailer\n<< /Size {} /Root 1 0 R{} >>\nstartxref\n{}\n%%EOF\n",
+                objects.len() + 1,
+                trailer,
+                xref
+            )
+            .bytes(),
+        );
+        pdf
+    }
+
+    fn simple() -> Vec<u8> {
+        classic(&["<< /Type /Catalog /Pages 2 0 R >>", PAGES, PAGE], "")
+    }
+
+    fn deflate(data: &[u8]) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    /// 目录在对象流中，交叉引用流使用FlateDecode和PNG Up预测
+    fn xref_stream() -> Vec<u8> {
+        let mut pdf = b"%PDF-1.5\n%\xe2\xe3\xcf\xd3\n".to_vec();
+        let mut offsets = vec![
+            object(&mut pdf, 2, PAGES.as_bytes()),
+            object(&mut pdf, 3, PAGE.as_bytes()),
+        ];
+        let objects = deflate(b"1 0 << /Type /Catalog /Pages 2 0 R >>");
+        let mut stream = format!(
+            "<< /Type /ObjStm /N 1 /First 4 /Filter /FlateDecode /Length {} >>\nstream\n",
+            objects.len()
+        )
+        .into_bytes();
+        stream.extend(objects);
+        stream.extend_from_slice(b"\nendstream");
+        offsets.push(object(&mut pdf, 4, &stream));
+        offsets.push(pdf.len() as u64);
+
+        // 每行：类型(1) 位置或对象流编号(4) 代数或序号(2)
+        let mut rows = vec![[0, 0, 0, 0, 0, 0xff, 0xff], [2, 0, 0, 0, 4, 0, 0]];
+        for offset in offsets {
+            let mut row = [1, 0, 0, 0, 0, 0, 0];
+            row[1..5].copy_from_slice(&(offset as u32).to_be_bytes());
+            rows.push(row);
+        }
+        let mut predicted = vec![];
+        let mut prev = [0u8; 7];
+        for row in rows {
+            predicted.push(2);
+            predicted.extend(row.iter().zip(prev).map(|(byte, up)| byte.wrapping_sub(up)));
+            prev = row;
+        }
+        let data = deflate(&predicted);
+        let mut stream = format!(
+            "<< /Type /XRef /Size 6 /W [1 4 2] /Root 1 0 R /Filter /FlateDecode \
+             /DecodeParms << /Predictor 12 /Columns 7 >> /Length {} >>\nstream\n",
+            data.len()
+        )
+        .into_bytes();
+        stream.extend(data);
+        stream.extend_from_slice(b"\nendstream");
+        let xref = object(&mut pdf, 5, &stream);
+        pdf.extend(format!("startxref\n{}\n%%EOF\n", xref).bytes());
+        pdf
+    }
+
+    /// 最后一个`key`之后的整数
+    fn last_int(pdf: &[u8], key: &[u8]) -> u64 {
+        let pos = pdf.windows(key.len()).rposition(|w| w == key).unwrap();
+        parse_int(pdf, pos + key.len()).unwrap().0
+    }
+
+    /// 像其他程序一样在末尾追加一个增量更新，替换`num`号对象
+    fn append_update(pdf: &[u8], num: u64, body: &str) -> Vec<u8> {
+        let mut pdf = pdf.to_vec();
+        let size = last_int(&pdf, b"/Size");
+        let prev = last_int(&pdf, b"startxref");
+        let offset = object(&mut pdf, num, body.as_bytes());
+        let xref = pdf.len();
+        pdf.extend(
+            format!(
+                "xref\n{} 1\n{:010} 00000 n \ntrailer\n<< /Size {} /Root 1 0 R /Prev {} >>\n\
+                 startxref\n{}\n%%EOF\n",
+                num, offset, size, prev, xref
+            )
+            .bytes(),
+        );
+        pdf
+    }
+
+    fn embed(carrier: &[u8]) -> Result<Vec<u8>> {
+        let options = EmbedOptions {
+            layout: Layout::PdfStream,
+            ..Default::default()
+        };
+        crate::embed_bytes(carrier, &[("a.txt", b"a")], &options)
+    }
+
+    /// 增量更新部分，即保存附件后新增的数据
+    fn update_part<'a>(output: &'a [u8], carrier: &[u8]) -> &'a [u8] {
+        assert!(output.starts_with(carrier));
+        &output[carrier.len()..]
+    }
+
+    fn contains(data: &[u8], part: &[u8]) -> bool {
+        data.windows(part.len()).any(|w| w == part)
+    }
+
+    #[test]
+    fn pdf_round_trip() {
+        let carrier = simple();
+        let output = round_trip(&carrier, Layout::PdfStream);
+        let update = update_part(&output, &carrier);
+        assert!(contains(update, b"/EmbeddedFiles"));
+        assert!(contains(update, FILE_NAME.as_bytes()));
+        assert!(contains(
+            update,
+            format!("/Prev {}", last_int(&carrier, b"startxref")).as_bytes()
+        ));
+        assert!(output.ends_with(b"%%EOF\n"));
+    }
+
+    #[test]
+    fn pdf_names_dict() {
+        // 目录中直接包含名称字典
+        let carrier = classic(
+            &[
+                "<< /Type /Catalog /Pages 2 0 R /Names << /Dests 4 0 R >> >>",
+                PAGES,
+                PAGE,
+                "<< /Names [(x) [3 0 R /Fit]] >>",
+            ],
+            "",
+        );
+        let output = round_trip(&carrier, Layout::PdfStream);
+        let update = update_part(&output, &carrier);
+        assert!(contains(update, b"/Dests 4 0 R"));
+        assert!(contains(update, b"/EmbeddedFiles"));
+
+        // 名称字典是间接对象时只替换名称字典
+        let carrier = classic(
+            &[
+                "<< /Type /Catalog /Pages 2 0 R /Names 4 0 R >>",
+                PAGES,
+                PAGE,
+                "<< /Dests << /Names [] >> >>",
+            ],
+            "",
+        );
+        let output = round_trip(&carrier, Layout::PdfStream);
+        let update = update_part(&output, &carrier);
+        assert!(contains(update, b"4 0 obj"));
+        assert!(!contains(update, b"1 0 obj"));
+    }
+
+    #[test]
+    fn pdf_xref_stream() {
+        let carrier = xref_stream();
+        let output = round_trip(&carrier, Layout::PdfStream);
+        // 新的交叉引用段也使用交叉引用流
+        let update = update_part(&output, &carrier);
+        assert!(contains(update, b"/Type /XRef"));
+        assert!(contains(update, b"/Prev"));
+    }
+
+    #[test]
+    fn pdf_carrier_with_update() {
+        let carrier = append_update(&simple(), 1, "<< /Type /Catalog /Pages 2 0 R /Lang (en) >>");
+        let output = round_trip(&carrier, Layout::PdfStream);
+        // 替换的是最新的目录
+        assert!(contains(update_part(&output, &carrier), b"/Lang (en)"));
+    }
+
+    #[test]
+    fn pdf_later_update() {
+        for carrier in [simple(), xref_stream()] {
+            let output = round_trip(&carrier, Layout::PdfStream);
+            // 其他程序之后追加的增量更新不影响识别，还原为保存附件之前的PDF
+            let mut updated = output;
+            for _ in 0..2 {
+                updated = append_update(&updated, 3, PAGE);
+                let attachments = inspect(&updated).unwrap().unwrap();
+                assert_eq!(attachments.layout, Layout::PdfStream);
+                let mut extractor =
+                    Extractor::new(Cursor::new(&updated), &ExtractOptions::default())
+                        .unwrap()
+                        .unwrap();
+                let mut original = vec![];
+                extractor.carrier(&mut original).unwrap();
+                assert!(original == carrier);
+            }
+        }
+    }
+
+    #[test]
+    fn pdf_foreign_update_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let carrier = dir.path().join("a.pdf");
+        let secret = dir.path().join("secret.txt");
+        fs::write(&secret, "secret").unwrap();
+        fs::write(&carrier, simple()).unwrap();
+        let options = EmbedOptions {
+            layout: Layout::PdfStream,
+            ..Default::default()
+        };
+        let cancel = CancelToken::new();
+        crate::embed(
+            &carrier,
+            std::slice::from_ref(&secret),
+            None,
+            &options,
+            &NoProgress,
+            &cancel,
+        )
+        .unwrap();
+        // 其他程序在附件之后追加了增量更新
+        let updated = append_update(&fs::read(&carrier).unwrap(), 3, PAGE);
+        fs::write(&carrier, &updated).unwrap();
+
+        let attachments = crate::inspect(&carrier, None, &NoProgress, &cancel)
+            .unwrap()
+            .unwrap();
+        let res = crate::strip(&carrier, &attachments, None, &NoProgress, &cancel);
+        assert!(matches!(res, Err(Error::InvalidInput(_))));
+        let res = crate::embed(&carrier, &[secret], None, &options, &NoProgress, &cancel);
+        assert!(matches!(res, Err(Error::InvalidInput(_))));
+        assert!(fs::read(&carrier).unwrap() == updated);
+        // 提取不受影响
+        let output = dir.path().join("out");
+        let options = ExtractOptions::default();
+        crate::extract_all(
+            &carrier,
+            &attachments,
+            &output,
+            &options,
+            &NoProgress,
+            &cancel,
+        )
+        .unwrap();
+        assert_eq!(fs::read(output.join("secret.txt")).unwrap(), b"secret");
+    }
+
+    #[test]
+    fn pdf_strip_in_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let carrier = dir.path().join("a.pdf");
+        let secret = dir.path().join("secret.txt");
+        fs::write(&secret, "secret").unwrap();
+        fs::write(&carrier, simple()).unwrap();
+        let options = EmbedOptions {
+            layout: Layout::PdfStream,
+            ..Default::default()
+        };
+        let cancel = CancelToken::new();
+        crate::embed(&carrier, &[secret], None, &options, &NoProgress, &cancel).unwrap();
+        let attachments = crate::inspect(&carrier, None, &NoProgress, &cancel)
+            .unwrap()
+            .unwrap();
+        crate::strip(&carrier, &attachments, None, &NoProgress, &cancel).unwrap();
+        assert!(fs::read(&carrier).unwrap() == simple());
+    }
+
+    #[test]
+    fn pdf_rejected() {
+        let encrypted = classic(
+            &["<< /Type /Catalog /Pages 2 0 R >>", PAGES, PAGE],
+            " /Encrypt 9 0 R",
+        );
+        let embedded = classic(
+            &[
+                "<< /Type /Catalog /Pages 2 0 R /Names << /EmbeddedFiles << /Names [] >> >> >>",
+                PAGES,
+                PAGE,
+            ],
+            "",
+        );
+        let mut truncated = simple();
+        truncated.truncate(truncated.len() - 30);
+        for carrier in [&b"not a pdf"[..], &encrypted, &embedded, &truncated] {
+            assert!(matches!(embed(carrier), Err(Error::InvalidInput(_))));
+        }
+    }
+
+    #[test]
+    fn pdf_bad_prev() {
+        // Prev超出文件长度，或者指向自己
+        let xref = last_int(&simple(), b"startxref");
+        for prev in [99999999, xref] {
+            let trailer = format!(" /Prev {}", prev);
+            let carrier = classic(
+                &["<< /Type /Catalog /Pages 2 0 R >>", PAGES, PAGE],
+                &trailer,
+            );
+            assert!(inspect(&carrier).unwrap().is_none());
+            let res = Extractor::new(Cursor::new(&carrier), &ExtractOptions::default());
+            assert!(matches!(res, Ok(None)));
+            round_trip(&carrier, Layout::PdfStream);
+        }
+    }
+
+    #[test]
+    fn pdf_huge_columns() {
+        for columns in ["18446744073709551615", "100000000000", "1000"] {
+            // 交叉引用流是最后一个对象，修改字典不影响其他对象的位置
+            let carrier = xref_stream();
+            let key = b"/Columns 7";
+            let pos = carrier.windows(key.len()).position(|w| w == key).unwrap();
+            let mut forged = carrier[..pos].to_vec();
+            forged.extend(format!("/Columns {}", columns).bytes());
+            forged.extend_from_slice(&carrier[pos + key.len()..]);
+            assert!(inspect(&forged).unwrap().is_none());
+            assert!(matches!(embed(&forged), Err(Error::InvalidInput(_))));
+        }
+    }
+
+    #[test]
+    fn pdf_without_attachment() {
+        assert!(inspect(&simple()).unwrap().is_none());
+        assert!(inspect(&xref_stream()).unwrap().is_none());
+    }
+}
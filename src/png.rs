@@ -0,0 +1,241 @@
+use std::io::{self, SeekFrom, Write};
+
+use crate::{
+    error::{Error, Result},
+    format::{ReadSeek, Scanner},
+    layout::Segments,
+    progress::ChunkProgress,
+};
+
+pub(crate) const PNG_MAGIC: &[u8] = b"\x89PNG\r\n\x1a\n";
+/// 保存附件的块类型：辅助块(h)、私有(i)、保留位(D)、可以安全复制(n)
+const HIDDEN_CHUNK: [u8; 4] = *b"hiDn";
+const IEND_CHUNK: [u8; 4] = *b"IEND";
+/// 每个附件块最多保存的数据长度，libpng默认拒绝超过8MB的辅助块
+pub(crate) const MAX_CHUNK_DATA: usize = 1024 * 1024;
+/// 块的长度(4) 类型(4) 校验和(4)
+const CHUNK_OVERHEAD: u64 = 12;
+
+/// PNG中一个块的位置
+pub(crate) struct Chunk {
+    pub offset: u64,
+    pub data_len: u32,
+    pub kind: [u8; 4],
+}
+
+impl Chunk {
+    /// 块结束位置，包括校验和
+    pub fn end(&self) -> u64 {
+        self.offset + CHUNK_OVERHEAD + self.data_len as u64
+    }
+}
+
+/// 依次读取每个块的位置，到IEND结束，不是PNG文件或者块结构错误时返回None
+pub(crate) fn read_chunks(
+    reader: &mut dyn ReadSeek,
+    len: u64,
+    progress: &mut ChunkProgress,
+) -> Result<Option<Vec<Chunk>>> {
+    let mut magic = [0; PNG_MAGIC.len()];
+    if len < magic.len() as u64 {
+        return Ok(None);
+    }
+    reader.seek(SeekFrom::Start(0))?;
+    reader.read_exact(&mut magic)?;
+    if magic != PNG_MAGIC {
+        return Ok(None);
+    }
+    let mut scanner = Scanner::new(reader, PNG_MAGIC.len() as u64, len)?;
+    let mut chunks = vec![];
+    while let Some(header) = scanner.read::<8>()? {
+        let chunk = Chunk {
+            offset: scanner.pos - 8,
+            data_len: u32::from_be_bytes([header[0], header[1], header[2], header[3]]),
+            kind: [header[4], header[5], header[6], header[7]],
+        };
+        // 块数据和4字节校验和
+        if !scanner.skip(chunk.data_len as u64 + 4)? {
+            return Ok(None);
+        }
+        progress.scan(scanner.pos)?;
+        let iend = chunk.kind == IEND_CHUNK;
+        chunks.push(chunk);
+        if iend {
+            return Ok(Some(chunks));
+        }
+    }
+    Ok(None)
+}
+
+/// # 查找保存附件的块
+///
+/// 返回去掉附件块之后的原始源文件各段，以及附件块中数据的各段，没有附件块时返回None。
+/// 附件块的校验和不检查，附件数据和头部都有自己的校验值
+pub(crate) fn hidden_segments(
+    reader: &mut dyn ReadSeek,
+    len: u64,
+    progress: &mut ChunkProgress,
+) -> Result<Option<(Segments, Segments)>> {
+    let chunks = match read_chunks(reader, len, progress)? {
+        Some(chunks) => chunks,
+        None => return Ok(None),
+    };
+    let mut carrier = vec![];
+    let mut data = vec![];
+    let mut start = 0;
+    for chunk in chunks.iter().filter(|chunk| chunk.kind == HIDDEN_CHUNK) {
+        carrier.push((start, chunk.offset - start));
+        data.push((chunk.offset + 8, chunk.data_len as u64));
+        start = chunk.end();
+    }
+    if data.is_empty() {
+        return Ok(None);
+    }
+    // IEND和之后的数据都属于原始源文件
+    carrier.push((start, len - start));
+    carrier.retain(|(_, len)| *len > 0);
+    data.retain(|(_, len)| *len > 0);
+    Ok(Some((carrier, data)))
+}
+
+/// 写入一个附件块，包括长度、类型和校验和
+pub(crate) fn write_chunk(output: &mut dyn Write, data: &[u8]) -> io::Result<()> {
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(&HIDDEN_CHUNK);
+    crc.update(data);
+    output.write_all(&(data.len() as u32).to_be_bytes())?;
+    output.write_all(&HIDDEN_CHUNK)?;
+    output.write_all(data)?;
+    output.write_all(&crc.finalize().to_be_bytes())
+}
+
+/// 附件块插入的位置，即IEND块的开始位置
+pub(crate) fn insert_point(
+    reader: &mut dyn ReadSeek,
+    len: u64,
+    progress: &mut ChunkProgress,
+) -> Result<u64> {
+    read_chunks(reader, len, progress)?
+        .and_then(|chunks| chunks.last().map(|chunk| chunk.offset))
+        .ok_or_else(|| Error::InvalidInput("源文件不是有效的PNG文件！".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, io::Cursor};
+
+    use super::*;
+    use crate::{
+        layout::{tests::round_trip, Layout},
+        progress::{CancelToken, Phase, ProgressEvent},
+        EmbedOptions,
+    };
+
+    fn write_raw_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+        let mut crc = crc32fast::Hasher::new();
+        crc.update(kind);
+        crc.update(data);
+        png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        png.extend_from_slice(kind);
+        png.extend_from_slice(data);
+        png.extend_from_slice(&crc.finalize().to_be_bytes());
+    }
+
+    /// 1x1的灰度PNG，IEND之前可以有若干附件块
+    fn png_with_chunks(count: usize) -> Vec<u8> {
+        let mut png = PNG_MAGIC.to_vec();
+        write_raw_chunk(&mut png, b"IHDR", &[0, 0, 0, 1, 0, 0, 0, 1, 8, 0, 0, 0, 0]);
+        // 压缩后的扫描行：过滤类型0、一个像素0
+        write_raw_chunk(
+            &mut png,
+            b"IDAT",
+            &[0x78, 0x01, 0x63, 0x60, 0, 0, 0, 0x02, 0, 0x01],
+        );
+        for _ in 0..count {
+            write_chunk(&mut png, &vec![0; MAX_CHUNK_DATA]).unwrap();
+        }
+        write_raw_chunk(&mut png, &IEND_CHUNK, &[]);
+        png
+    }
+
+    /// 依次检查每个块的校验和，返回块类型
+    fn check_crc(png: &[u8]) -> Vec<[u8; 4]> {
+        let mut kinds = vec![];
+        let mut pos = PNG_MAGIC.len();
+        while pos < png.len() {
+            let len = u32::from_be_bytes(png[pos..pos + 4].try_into().unwrap()) as usize;
+            let body = &png[pos + 4..pos + 8 + len];
+            let crc = u32::from_be_bytes(png[pos + 8 + len..pos + 12 + len].try_into().unwrap());
+            assert_eq!(crc32fast::hash(body), crc);
+            kinds.push(body[..4].try_into().unwrap());
+            pos += 12 + len;
+        }
+        kinds
+    }
+
+    #[test]
+    fn png_round_trip() {
+        let png = png_with_chunks(0);
+        let output = round_trip(&png, Layout::PngChunk);
+        let kinds = check_crc(&output);
+        assert_eq!(&kinds[..2], [*b"IHDR", *b"IDAT"]);
+        assert_eq!(kinds.last(), Some(&IEND_CHUNK));
+        // 附件超过1MB，分成两个附件块
+        assert_eq!(
+            kinds.iter().filter(|kind| **kind == HIDDEN_CHUNK).count(),
+            2
+        );
+        assert!(output.ends_with(&png[png.len() - CHUNK_OVERHEAD as usize..]));
+    }
+
+    #[test]
+    fn png_trailing_data_kept() {
+        // IEND之后的其他数据属于原始源文件，移除附件后还原
+        let mut png = png_with_chunks(0);
+        png.extend_from_slice(b"trailing data");
+        let output = round_trip(&png, Layout::PngChunk);
+        assert!(output.ends_with(b"trailing data"));
+    }
+
+    #[test]
+    fn png_invalid_carrier() {
+        let options = EmbedOptions {
+            layout: Layout::PngChunk,
+            ..Default::default()
+        };
+        let mut png = png_with_chunks(0);
+        png.truncate(png.len() - CHUNK_OVERHEAD as usize);
+        for carrier in [&b"not a png"[..], &png] {
+            let res = crate::embed_bytes(carrier, &[("a.txt", b"a")], &options);
+            assert!(matches!(res, Err(Error::InvalidInput(_))));
+        }
+    }
+
+    #[test]
+    fn scan_reports_progress() {
+        let png = png_with_chunks(3);
+        let events = Cell::new(0);
+        let progress = |event: &ProgressEvent| {
+            assert_eq!(event.phase, Phase::Inspect);
+            events.set(events.get() + 1);
+        };
+        let cancel = CancelToken::new();
+        let mut chunks = ChunkProgress::new(Phase::Inspect, png.len() as u64, &progress, &cancel);
+        let (_, data) = hidden_segments(&mut Cursor::new(&png), png.len() as u64, &mut chunks)
+            .unwrap()
+            .unwrap();
+        assert_eq!(data.len(), 3);
+        assert!(events.get() >= 2);
+    }
+
+    #[test]
+    fn scan_cancelled() {
+        let png = png_with_chunks(3);
+        let cancel = CancelToken::new();
+        // 第一次通知进度时取消，之后的块不再读取
+        let progress = |_: &ProgressEvent| cancel.cancel();
+        let mut chunks = ChunkProgress::new(Phase::Inspect, png.len() as u64, &progress, &cancel);
+        let res = hidden_segments(&mut Cursor::new(&png), png.len() as u64, &mut chunks);
+        assert!(matches!(res, Err(Error::Cancelled)));
+    }
+}
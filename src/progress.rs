@@ -0,0 +1,183 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use crate::error::{Error, Result};
+
+/// 当前执行的阶段
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// 读取附件信息
+    Inspect,
+    /// 复制源文件
+    Carrier,
+    /// 写入附件
+    Embed,
+    /// 提取附件
+    Extract,
+    /// 校验附件
+    Verify,
+    /// 移除附件，还原源文件
+    Strip,
+}
+
+/// 进度事件
+#[derive(Clone, Copy, Debug)]
+pub struct ProgressEvent {
+    pub phase: Phase,
+    /// 已处理的字节数
+    pub bytes_done: u64,
+    /// 需要处理的总字节数，保存附件时为源文件和附件的原始大小之和
+    pub bytes_total: u64,
+    /// 平均速度(字节/秒)
+    pub throughput: f64,
+}
+
+impl ProgressEvent {
+    /// 进度百分比(0-100)
+    pub fn percent(&self) -> i32 {
+        if self.bytes_total == 0 {
+            return 100;
+        }
+        (self.bytes_done.min(self.bytes_total) as f64 / self.bytes_total as f64 * 100.) as i32
+    }
+}
+
+/// # 接收进度事件
+///
+/// 每复制一块数据(1MB)通知一次，操作完成时再通知一次，闭包`Fn(&ProgressEvent)`可以直接使用
+pub trait Progress {
+    fn report(&self, event: &ProgressEvent);
+}
+
+impl<F: Fn(&ProgressEvent)> Progress for F {
+    fn report(&self, event: &ProgressEvent) {
+        self(event)
+    }
+}
+
+/// 不需要进度时使用
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoProgress;
+
+impl Progress for NoProgress {
+    fn report(&self, _event: &ProgressEvent) {}
+}
+
+/// # 取消令牌
+///
+/// 克隆后共享同一个状态，在任意线程调用`cancel`，正在执行的操作在下一块数据时返回错误
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// 扫描文件结构时通知进度的间隔，与复制数据时每块的大小相同
+const SCAN_STEP: u64 = 1024 * 1024;
+
+/// 分块复制时的进度
+pub(crate) struct ChunkProgress<'a> {
+    phase: Phase,
+    current: u64,
+    total: u64,
+    /// 上次通知进度时扫描到的位置
+    scanned: u64,
+    started: Instant,
+    progress: &'a dyn Progress,
+    cancel: &'a CancelToken,
+}
+
+impl<'a> ChunkProgress<'a> {
+    pub fn new(
+        phase: Phase,
+        total: u64,
+        progress: &'a dyn Progress,
+        cancel: &'a CancelToken,
+    ) -> Self {
+        Self {
+            phase,
+            current: 0,
+            total,
+            scanned: 0,
+            started: Instant::now(),
+            progress,
+            cancel,
+        }
+    }
+
+    /// 切换阶段，已处理的字节数继续累加
+    pub fn set_phase(&mut self, phase: Phase) {
+        self.phase = phase;
+    }
+
+    /// 检查是否取消了当前操作
+    pub fn check(&self) -> Result<()> {
+        if self.cancel.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+        Ok(())
+    }
+
+    /// 每复制一块数据通知进度，并检查是否取消当前操作
+    pub fn advance(&mut self, len: usize) -> Result<()> {
+        self.current += len as u64;
+        self.report(self.current, self.total);
+        self.check()
+    }
+
+    /// 扫描文件结构时检查是否取消当前操作，读取附件信息时每扫描1MB通知一次进度。
+    /// 扫描的位置不计入已处理的字节数
+    pub fn scan(&mut self, pos: u64) -> Result<()> {
+        if self.phase == Phase::Inspect && pos >= self.scanned + SCAN_STEP {
+            self.scanned = pos;
+            self.report(pos, self.total);
+        }
+        self.check()
+    }
+
+    /// 操作完成，通知100%进度
+    pub fn finish(&self) {
+        let total = self.total.max(self.current);
+        self.report(total, total);
+    }
+
+    fn report(&self, bytes_done: u64, bytes_total: u64) {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        self.progress.report(&ProgressEvent {
+            phase: self.phase,
+            bytes_done,
+            bytes_total,
+            throughput: if elapsed > 0. {
+                self.current as f64 / elapsed
+            } else {
+                0.
+            },
+        });
+    }
+}
+
+/// 不通知进度也不能取消，流式接口和不需要进度的内部操作使用
+pub(crate) fn no_progress<T>(
+    phase: Phase,
+    total: u64,
+    f: impl FnOnce(&mut ChunkProgress) -> T,
+) -> T {
+    let cancel = CancelToken::new();
+    f(&mut ChunkProgress::new(phase, total, &NoProgress, &cancel))
+}
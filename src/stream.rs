@@ -0,0 +1,531 @@
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{self, Cursor, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use crate::{
+    codec::{self, Codec, CompressWriter},
+    container::{self, Attachments, Entry, EntryKind, Manifest},
+    crypto::{self, DecryptReader, EncryptWriter, HashReader, HashWriter, KdfParams, HASH_LEN},
+    error::{Error, Result},
+    layout::{self, Layout, SegmentReader},
+    progress::{no_progress, ChunkProgress, Phase},
+    tree,
+    utils::{copy_chunks, EmbedOptions, ExtractOptions, VerifyStatus},
+};
+
+/// 复制数据时使用的缓冲区大小
+pub(crate) const BUF_SIZE: usize = 1024 * 1024;
+
+/// 记录写入的长度，用来计算附件的位置，输出不需要支持Seek
+struct CountWriter<W: Write> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for CountWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(data)?;
+        self.count += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// # 流式写入附件
+///
+/// 输出中先写入源文件，然后依次添加附件，最后调用`finish`写入头部和尾部。
+/// 输出只需要支持`Write`，可以直接写入网络连接或者内存。
+/// 附件总是追加在末尾，`EmbedOptions::layout`不是`Append`时返回错误，保存到文件内部请使用`embed_bytes`
+pub struct Embedder<W: Write> {
+    output: CountWriter<W>,
+    options: EmbedOptions,
+    manifest: Manifest,
+    key: Option<chacha20poly1305::Key>,
+    names: HashSet<String>,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> Embedder<W> {
+    /// 从输出的当前位置开始写入附件，输出中应该已经有源文件的内容
+    pub fn new(output: W, options: &EmbedOptions) -> Result<Self> {
+        if options.layout != Layout::Append {
+            return Err(Error::InvalidInput(
+                "流式写入只能把附件追加在末尾！".to_string(),
+            ));
+        }
+        Self::for_layout(output, options)
+    }
+
+    /// 不检查保存方式，保存到文件内部时附件数据同样连续写入，由调用方插入到源文件中
+    pub(crate) fn for_layout(output: W, options: &EmbedOptions) -> Result<Self> {
+        if options.encrypt_metadata && options.password.is_none() {
+            return Err(Error::PasswordRequired);
+        }
+        let mut manifest = Manifest::default();
+        let key = match &options.password {
+            Some(password) => {
+                let params = KdfParams::generate();
+                let key = params.derive_key(password)?;
+                manifest.encryption = Some(params);
+                Some(key)
+            }
+            None => None,
+        };
+        Ok(Self {
+            output: CountWriter {
+                inner: output,
+                count: 0,
+            },
+            options: options.clone(),
+            manifest,
+            key,
+            names: HashSet::new(),
+            buf: vec![0; BUF_SIZE],
+        })
+    }
+
+    /// 先把源文件复制到输出中，同时记录源文件的校验值，移除附件时用来校验
+    pub fn with_carrier<R: Read>(carrier: R, output: W, options: &EmbedOptions) -> Result<Self> {
+        let mut embedder = Self::new(output, options)?;
+        no_progress(Phase::Carrier, 0, |chunks| {
+            embedder.copy_carrier(carrier, chunks)
+        })?;
+        Ok(embedder)
+    }
+
+    pub(crate) fn copy_carrier<R: Read>(
+        &mut self,
+        mut carrier: R,
+        chunks: &mut ChunkProgress,
+    ) -> Result<()> {
+        let mut writer = HashWriter::new(&mut self.output);
+        copy_chunks(&mut carrier, &mut writer, &mut self.buf, chunks)?;
+        self.manifest.carrier_sha256 = Some(writer.finish().1);
+        Ok(())
+    }
+
+    /// 源文件没有复制到输出中时，使用已知的原始源文件校验值
+    pub(crate) fn set_carrier_sha256(&mut self, carrier_sha256: Option<[u8; HASH_LEN]>) {
+        self.manifest.carrier_sha256 = carrier_sha256;
+    }
+
+    /// 添加一个文件，文件夹中的文件名称使用`/`分隔
+    pub fn add_file<R: Read>(&mut self, name: &str, reader: R) -> Result<()> {
+        no_progress(Phase::Embed, 0, |chunks| {
+            self.add_entry(
+                name.to_string(),
+                EntryKind::File,
+                Some(reader),
+                None,
+                None,
+                chunks,
+            )
+        })
+    }
+
+    /// 添加一个文件夹，用来保留空文件夹
+    pub fn add_dir(&mut self, name: &str) -> Result<()> {
+        no_progress(Phase::Embed, 0, |chunks| {
+            self.add_entry::<io::Empty>(name.to_string(), EntryKind::Dir, None, None, None, chunks)
+        })
+    }
+
+    /// 添加磁盘上的文件或文件夹，文件夹按照相对路径展开为多个附件
+    pub(crate) fn add_path(
+        &mut self,
+        path: &Path,
+        name: &str,
+        chunks: &mut ChunkProgress,
+    ) -> Result<()> {
+        for item in tree::walk_dir(path, name)? {
+            let (mode, mtime) = match self.options.keep_metadata {
+                true => (item.mode, item.mtime),
+                false => (None, None),
+            };
+            let reader = match item.kind {
+                EntryKind::File => Some(File::open(&item.path)?),
+                EntryKind::Dir => None,
+            };
+            self.add_entry(item.name, item.kind, reader, mode, mtime, chunks)?;
+        }
+        Ok(())
+    }
+
+    fn add_entry<R: Read>(
+        &mut self,
+        name: String,
+        kind: EntryKind,
+        reader: Option<R>,
+        mode: Option<u32>,
+        mtime: Option<u64>,
+        chunks: &mut ChunkProgress,
+    ) -> Result<()> {
+        chunks.check()?;
+        if !self.names.insert(name.clone()) {
+            return Err(Error::InvalidInput(format!("附件名称重复:{}", name)));
+        }
+        // 附件数据依次经过 压缩 -> 加密 写入输出文件
+        let start = self.output.count;
+        let codec = match kind {
+            EntryKind::File => self.options.compression,
+            EntryKind::Dir => Codec::None,
+        };
+        // 校验值根据最终写入输出文件的数据计算，校验时不需要密码
+        let (size, nonce, sha256) = match (reader, &self.key) {
+            (Some(mut reader), Some(key)) => {
+                let nonce = crypto::generate_nonce();
+                let mut writer = CompressWriter::new(
+                    codec,
+                    EncryptWriter::new(HashWriter::new(&mut self.output), key, &nonce),
+                )?;
+                let size = copy_chunks(&mut reader, &mut writer, &mut self.buf, chunks)?;
+                let (_, sha256) = writer.finish()?.finish()?.finish();
+                (size, Some(nonce), Some(sha256))
+            }
+            (Some(mut reader), None) => {
+                let mut writer = CompressWriter::new(codec, HashWriter::new(&mut self.output))?;
+                let size = copy_chunks(&mut reader, &mut writer, &mut self.buf, chunks)?;
+                let (_, sha256) = writer.finish()?.finish();
+                (size, None, Some(sha256))
+            }
+            (None, _) => (0, None, None),
+        };
+        let stored_len = self.output.count - start;
+        self.manifest.entries.push(Entry {
+            name,
+            size,
+            offset: self.manifest.data_len,
+            kind,
+            mode,
+            mtime,
+            stored_len,
+            nonce,
+            codec,
+            sha256,
+        });
+        self.manifest.data_len += stored_len;
+        Ok(())
+    }
+
+    /// 写入头部和尾部，返回输出
+    pub fn finish(mut self) -> Result<W> {
+        if self.manifest.entries.is_empty() {
+            return Err(Error::InvalidInput("没有附加文件！".to_string()));
+        }
+        let seal_key = self.key.as_ref().filter(|_| self.options.encrypt_metadata);
+        container::write_trailer(&mut self.output, &self.manifest, seal_key)?;
+        self.output.flush()?;
+        Ok(self.output.inner)
+    }
+}
+
+/// # 从源文件中读取附件
+///
+/// 源文件需要支持`Read + Seek`，可以是文件或者内存中的数据，支持所有的保存方式
+pub struct Extractor<R: Read + Seek> {
+    reader: SegmentReader<R>,
+    attachments: Attachments,
+    options: ExtractOptions,
+    key: Option<chacha20poly1305::Key>,
+    buf: Vec<u8>,
+}
+
+impl<R: Read + Seek> Extractor<R> {
+    /// 读取源文件末尾的附件信息，没有附件时返回None
+    pub fn new(reader: R, options: &ExtractOptions) -> Result<Option<Self>> {
+        let password = options.password.as_deref();
+        match no_progress(Phase::Inspect, 0, |chunks| {
+            layout::read_attachments(reader, password, chunks)
+        })? {
+            (reader, Some(attachments)) => Self::open(reader, attachments, options).map(Some),
+            (_, None) => Ok(None),
+        }
+    }
+
+    /// 使用已经读取到的附件信息
+    pub fn with_attachments(
+        reader: R,
+        attachments: Attachments,
+        options: &ExtractOptions,
+    ) -> Result<Self> {
+        let (_, reader) = no_progress(Phase::Inspect, 0, |chunks| {
+            layout::open(reader, Some(attachments.layout), chunks)
+        })?;
+        Self::open(reader, attachments, options)
+    }
+
+    fn open(
+        reader: SegmentReader<R>,
+        attachments: Attachments,
+        options: &ExtractOptions,
+    ) -> Result<Self> {
+        if attachments.sealed {
+            return Err(Error::PasswordRequired);
+        }
+        Ok(Self {
+            reader,
+            attachments,
+            options: options.clone(),
+            key: None,
+            buf: vec![0; BUF_SIZE],
+        })
+    }
+
+    pub fn attachments(&self) -> &Attachments {
+        &self.attachments
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader.into_inner()
+    }
+
+    /// 根据密码生成解密附件的密钥，只在第一次提取加密的附件时生成
+    fn key(&mut self) -> Result<Option<chacha20poly1305::Key>> {
+        if self.key.is_none() {
+            self.key = match (&self.attachments.encryption, &self.options.password) {
+                (Some(params), Some(password)) => Some(params.derive_key(password)?),
+                (Some(_), None) => return Err(Error::PasswordRequired),
+                (None, _) => None,
+            };
+        }
+        Ok(self.key)
+    }
+
+    /// 提取一个附件写入到输出
+    pub fn extract<W: Write>(&mut self, entry: &Entry, mut output: W) -> Result<()> {
+        no_progress(Phase::Extract, entry.size, |chunks| {
+            self.extract_with(entry, &mut output, chunks)
+        })
+    }
+
+    /// 提取一个附件到内存
+    pub fn read(&mut self, entry: &Entry) -> Result<Vec<u8>> {
+        let mut data = vec![];
+        self.extract(entry, &mut data)?;
+        Ok(data)
+    }
+
+    pub(crate) fn extract_with<W: Write>(
+        &mut self,
+        entry: &Entry,
+        output: &mut W,
+        chunks: &mut ChunkProgress,
+    ) -> Result<()> {
+        if entry.codec != Codec::None && entry.size > self.options.max_decompressed_size {
+            return Err(Error::SizeLimit {
+                name: entry.name.clone(),
+                size: entry.size,
+            });
+        }
+        let key = match entry.nonce {
+            Some(_) => self.key()?,
+            None => None,
+        };
+        let (start_offset, end_offset) = self.attachments.range(entry);
+        log::debug!("开始提取附件start_offset={start_offset} end_offset={end_offset}");
+
+        self.reader.seek(SeekFrom::Start(start_offset))?;
+        let mut hasher = Sha256::new();
+        let stored = HashReader::new((&mut self.reader).take(entry.stored_len), &mut hasher);
+        let decrypted: Box<dyn Read> = match (&entry.nonce, &key) {
+            (Some(nonce), Some(key)) => {
+                Box::new(DecryptReader::new(stored, key, nonce, entry.stored_len))
+            }
+            (Some(_), None) => return Err(Error::PasswordRequired),
+            (None, _) => Box::new(stored),
+        };
+        // 最多多读1个字节，用来检测解压后的数据是否超过记录的长度
+        let mut reader = codec::decompress_reader(entry.codec, decrypted)?.take(entry.size + 1);
+
+        let current = copy_chunks(&mut reader, output, &mut self.buf, chunks)?;
+        log::debug!("文件提取结束 写入长度:{current}");
+        drop(reader);
+        // 解压时可能没有读完附件数据，剩余的部分也要计算校验值
+        let remaining = end_offset.saturating_sub(self.reader.stream_position()?);
+        io::copy(&mut (&mut self.reader).take(remaining), &mut hasher)?;
+        check_hash(entry, hasher)?;
+        if current < entry.size {
+            return Err(Error::Corrupted("附件数据不完整！".to_string()));
+        }
+        if current > entry.size {
+            return Err(Error::Corrupted(
+                "附件解压后的长度与记录不一致！".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// 校验全部附件，只计算附件数据的校验值，不需要解密
+    pub fn verify(&mut self) -> Result<Vec<(String, VerifyStatus)>> {
+        no_progress(Phase::Verify, 0, |chunks| self.verify_with(chunks))
+    }
+
+    pub(crate) fn verify_with(
+        &mut self,
+        chunks: &mut ChunkProgress,
+    ) -> Result<Vec<(String, VerifyStatus)>> {
+        let mut results = vec![];
+        for entry in &self.attachments.entries {
+            let status = if entry.kind == EntryKind::Dir {
+                VerifyStatus::Ok
+            } else if entry.sha256.is_none() {
+                VerifyStatus::Unchecked
+            } else {
+                let (start_offset, _) = self.attachments.range(entry);
+                self.reader.seek(SeekFrom::Start(start_offset))?;
+                let mut hasher = Sha256::new();
+                let mut reader =
+                    HashReader::new((&mut self.reader).take(entry.stored_len), &mut hasher);
+                let current = copy_chunks(&mut reader, &mut io::sink(), &mut self.buf, chunks)?;
+                if current == entry.stored_len && check_hash(entry, hasher).is_ok() {
+                    VerifyStatus::Ok
+                } else {
+                    VerifyStatus::Corrupted
+                }
+            };
+            log::debug!("附件校验 {}:{:?}", entry.name, status);
+            results.push((entry.name.clone(), status));
+        }
+        Ok(results)
+    }
+
+    /// 把原始源文件(不包含附件)写入到输出，保存了原始源文件的校验值时同时校验
+    pub fn carrier<W: Write>(&mut self, mut output: W) -> Result<()> {
+        no_progress(Phase::Strip, self.attachments.data_start, |chunks| {
+            self.carrier_with(&mut output, chunks)
+        })
+    }
+
+    pub(crate) fn carrier_with<W: Write>(
+        &mut self,
+        output: &mut W,
+        chunks: &mut ChunkProgress,
+    ) -> Result<()> {
+        self.reader.seek(SeekFrom::Start(0))?;
+        let mut reader = (&mut self.reader).take(self.attachments.data_start);
+        let mut writer = HashWriter::new(output);
+        let current = copy_chunks(&mut reader, &mut writer, &mut self.buf, chunks)?;
+        let (_, sha256) = writer.finish();
+        if current != self.attachments.data_start {
+            return Err(Error::Corrupted("源文件数据不完整！".to_string()));
+        }
+        match self.attachments.carrier_sha256 {
+            Some(expected) if expected != sha256 => {
+                Err(Error::Corrupted("源文件校验失败，无法还原！".to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// 比较附件数据的校验值，没有保存校验值时跳过
+fn check_hash(entry: &Entry, hasher: Sha256) -> Result<()> {
+    match entry.sha256 {
+        Some(expected) if hasher.finalize().as_slice() != expected => Err(Error::Corrupted(
+            format!("附件{}校验失败，数据已损坏！", entry.name),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// # 在内存中把附件保存到源文件中
+///
+/// 参数:
+/// * `carrier`: 源文件内容
+/// * `files`: 附件名称和内容
+/// * `options`: 加密、压缩等选项
+pub fn embed_bytes(
+    carrier: &[u8],
+    files: &[(&str, &[u8])],
+    options: &EmbedOptions,
+) -> Result<Vec<u8>> {
+    let output = Vec::with_capacity(carrier.len() + files.iter().map(|f| f.1.len()).sum::<usize>());
+    no_progress(Phase::Carrier, 0, |chunks| {
+        layout::embed(
+            &mut Cursor::new(carrier),
+            output,
+            options,
+            chunks,
+            |embedder, _| {
+                files
+                    .iter()
+                    .try_for_each(|(name, data)| embedder.add_file(name, *data))
+            },
+        )
+    })
+}
+
+/// 提取到内存中的附件和内容
+pub type ExtractedFile = (Entry, Vec<u8>);
+
+/// # 在内存中提取全部附件
+///
+/// 没有附件时返回None，只返回文件，不包含文件夹
+///
+/// 参数:
+/// * `data`: 源文件内容
+/// * `options`: 密码、解压大小限制等选项
+pub fn extract_bytes(data: &[u8], options: &ExtractOptions) -> Result<Option<Vec<ExtractedFile>>> {
+    let mut extractor = match Extractor::new(Cursor::new(data), options)? {
+        Some(extractor) => extractor,
+        None => return Ok(None),
+    };
+    let entries: Vec<Entry> = extractor
+        .attachments()
+        .entries
+        .iter()
+        .filter(|entry| entry.kind == EntryKind::File)
+        .cloned()
+        .collect();
+    let mut files = vec![];
+    for entry in entries {
+        let data = extractor.read(&entry)?;
+        files.push((entry, data));
+    }
+    Ok(Some(files))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn embedder_rejects_in_file_layout() {
+        let options = EmbedOptions {
+            layout: Layout::PngChunk,
+            ..Default::default()
+        };
+        let res = Embedder::new(vec![], &options);
+        assert!(matches!(res, Err(Error::InvalidInput(_))));
+        assert!(Embedder::new(vec![], &EmbedOptions::default()).is_ok());
+    }
+
+    #[test]
+    fn wrong_password() {
+        let options = EmbedOptions {
+            password: Some("123456".to_string()),
+            ..Default::default()
+        };
+        let output = embed_bytes(b"carrier", &[("a.txt", b"secret")], &options).unwrap();
+        // 派生密钥较慢，正确密码和数据被修改在加密模块中测试
+        let options = ExtractOptions {
+            password: Some("654321".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            extract_bytes(&output, &options),
+            Err(Error::WrongPassword)
+        ));
+        assert!(matches!(
+            extract_bytes(&output, &ExtractOptions::default()),
+            Err(Error::PasswordRequired)
+        ));
+    }
+}
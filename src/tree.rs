@@ -0,0 +1,208 @@
+use std::{
+    fs::{self, File, Metadata},
+    io,
+    path::{Component, Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    container::EntryKind,
+    error::{Error, Result},
+};
+
+/// 文件夹中的一项
+pub(crate) struct TreeItem {
+    pub path: PathBuf,
+    /// 使用`/`分隔的相对路径，包含最外层文件夹的名称
+    pub name: String,
+    pub kind: EntryKind,
+    pub size: u64,
+    pub mode: Option<u32>,
+    pub mtime: Option<u64>,
+}
+
+/// 保存文件夹时文件夹中的某一项无法保存，返回错误而不是跳过，避免附件不完整
+fn unsupported(reason: &str, path: &Path) -> Error {
+    Error::InvalidInput(format!("文件夹中有{}，无法保存:{}", reason, path.display()))
+}
+
+/// 递归列出文件夹中的所有文件和文件夹，文件夹排在其内容之前
+///
+/// 只支持普通文件和文件夹，有符号链接、设备文件等或者文件名不是UTF-8时返回错误
+pub(crate) fn walk_dir(root: &Path, name: &str) -> Result<Vec<TreeItem>> {
+    let mut items = vec![];
+    walk(root, name.to_string(), &fs::metadata(root)?, &mut items)?;
+    Ok(items)
+}
+
+fn walk(path: &Path, name: String, meta: &Metadata, items: &mut Vec<TreeItem>) -> Result<()> {
+    let file_type = meta.file_type();
+    if file_type.is_symlink() {
+        return Err(unsupported("符号链接", path));
+    }
+    if !file_type.is_file() && !file_type.is_dir() {
+        return Err(unsupported("不支持的文件类型", path));
+    }
+    if file_type.is_file() {
+        items.push(TreeItem {
+            path: path.to_path_buf(),
+            name,
+            kind: EntryKind::File,
+            size: meta.len(),
+            mode: file_mode(meta),
+            mtime: file_mtime(meta),
+        });
+        return Ok(());
+    }
+    items.push(TreeItem {
+        path: path.to_path_buf(),
+        name: name.clone(),
+        kind: EntryKind::Dir,
+        size: 0,
+        mode: file_mode(meta),
+        mtime: file_mtime(meta),
+    });
+
+    let mut children = fs::read_dir(path)?.collect::<io::Result<Vec<_>>>()?;
+    children.sort_by_key(|child| child.file_name());
+    for child in children {
+        let meta = fs::symlink_metadata(child.path())?;
+        let child_name = match child.file_name().to_str() {
+            Some(child_name) => format!("{name}/{child_name}"),
+            None => return Err(unsupported("无效的文件名", &child.path())),
+        };
+        walk(&child.path(), child_name, &meta, items)?;
+    }
+    Ok(())
+}
+
+/// 文件夹中所有文件的总大小
+pub(crate) fn dir_size(root: &Path) -> Result<u64> {
+    Ok(walk_dir(root, "")?.iter().map(|item| item.size).sum())
+}
+
+/// 将附件的相对路径转换为输出文件夹中的路径，拒绝绝对路径和`..`
+pub(crate) fn output_path(output_dir: &Path, name: &str) -> Result<PathBuf> {
+    let mut path = output_dir.to_path_buf();
+    for part in name.split('/') {
+        let valid = !part.contains(['\\', ':'])
+            && matches!(
+                Path::new(part).components().next(),
+                Some(Component::Normal(_))
+            )
+            && Path::new(part).components().count() == 1;
+        if !valid {
+            return Err(Error::CorruptTrailer(format!("附件名称无效:{}", name)));
+        }
+        path.push(part);
+    }
+    Ok(path)
+}
+
+/// 修改时间(UNIX时间戳，秒)
+fn file_mtime(meta: &Metadata) -> Option<u64> {
+    let mtime = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some(mtime.as_secs())
+}
+
+/// 打开文件或文件夹用来修改时间，Windows上打开文件夹需要FILE_FLAG_BACKUP_SEMANTICS
+#[cfg(windows)]
+fn open_for_times(path: &Path, _kind: EntryKind) -> io::Result<File> {
+    use std::os::windows::fs::OpenOptionsExt;
+    // FILE_WRITE_ATTRIBUTES
+    File::options()
+        .access_mode(0x100)
+        .custom_flags(0x0200_0000)
+        .open(path)
+}
+
+#[cfg(not(windows))]
+fn open_for_times(path: &Path, kind: EntryKind) -> io::Result<File> {
+    match kind {
+        EntryKind::File => File::options().write(true).open(path),
+        EntryKind::Dir => File::open(path),
+    }
+}
+
+/// 恢复文件或文件夹的修改时间
+pub(crate) fn set_file_mtime(path: &Path, kind: EntryKind, mtime: Option<u64>) -> io::Result<()> {
+    match mtime {
+        Some(mtime) => open_for_times(path, kind)?
+            .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(mtime)),
+        None => Ok(()),
+    }
+}
+
+/// 文件权限(unix)，其他平台为None
+#[cfg(unix)]
+pub(crate) fn file_mode(meta: &Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(meta.permissions().mode())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn file_mode(_meta: &Metadata) -> Option<u32> {
+    None
+}
+
+/// 恢复文件权限，非unix平台忽略。
+/// 权限来自源文件，不能信任，去掉setuid、setgid，只有文件夹保留sticky位
+#[cfg(unix)]
+pub(crate) fn set_file_mode(path: &Path, kind: EntryKind, mode: Option<u32>) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mask = match kind {
+        EntryKind::File => 0o777,
+        EntryKind::Dir => 0o1777,
+    };
+    match mode {
+        Some(mode) => fs::set_permissions(path, fs::Permissions::from_mode(mode & mask)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn set_file_mode(_path: &Path, _kind: EntryKind, _mode: Option<u32>) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::{fs::PermissionsExt, net::UnixListener};
+
+    use super::*;
+
+    fn mode(path: &Path) -> u32 {
+        fs::metadata(path).unwrap().permissions().mode() & 0o7777
+    }
+
+    #[test]
+    fn mode_masked() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("a.sh");
+        fs::write(&file, "").unwrap();
+        set_file_mode(&file, EntryKind::File, Some(0o106755)).unwrap();
+        assert_eq!(mode(&file), 0o755);
+        let sub = dir.path().join("sub");
+        fs::create_dir(&sub).unwrap();
+        set_file_mode(&sub, EntryKind::Dir, Some(0o43777)).unwrap();
+        assert_eq!(mode(&sub), 0o1777);
+    }
+
+    #[test]
+    fn walk_rejects_special_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("docs");
+        fs::create_dir(&root).unwrap();
+        fs::write(root.join("a.txt"), "a").unwrap();
+        assert_eq!(walk_dir(&root, "docs").unwrap().len(), 2);
+
+        std::os::unix::fs::symlink(root.join("a.txt"), root.join("link")).unwrap();
+        let err = walk_dir(&root, "docs").err().unwrap();
+        assert!(matches!(&err, Error::InvalidInput(msg) if msg.contains("link")));
+        fs::remove_file(root.join("link")).unwrap();
+
+        let _socket = UnixListener::bind(root.join("socket")).unwrap();
+        let err = walk_dir(&root, "docs").err().unwrap();
+        assert!(matches!(&err, Error::InvalidInput(msg) if msg.contains("socket")));
+    }
+}
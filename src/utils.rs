@@ -1,20 +1,22 @@
-use anyhow::anyhow;
-use bincode::{config, Decode, Encode};
+use bincode::{Decode, Encode};
 use byte_unit::Byte;
-use rfd::FileDialog;
-use slint::SharedString;
 use std::{
-    fs::{self, File},
-    io::{Read, Write},
-    os::windows::prelude::{FileExt, MetadataExt},
-    path::PathBuf,
-    sync::{Arc, RwLock},
+    collections::HashSet,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
 };
 
-use crate::slint_generatedApp;
-
-const START_BYTES: &str = "RUSTAPPEND666S";
-const END_BYTES: &str = "RUSTAPPEND666E";
+use crate::{
+    codec::Codec,
+    container::{Attachments, Entry, EntryKind},
+    crypto,
+    error::{Error, Result},
+    layout::{self, Layout},
+    progress::{CancelToken, ChunkProgress, Phase, Progress},
+    stream::{Embedder, Extractor, BUF_SIZE},
+    tree,
+};
 
 #[derive(Clone, Debug, Default, Encode, Decode)]
 pub struct FileSpec {
@@ -25,29 +27,6 @@ pub struct FileSpec {
     pub extension: String,
 }
 
-impl From<&slint_generatedApp::FileSpec> for FileSpec {
-    fn from(f: &slint_generatedApp::FileSpec) -> Self {
-        Self {
-            path: f.path.to_string(),
-            name: f.name.to_string(),
-            size: f.size.to_string().parse().unwrap(),
-            sizemb: f.sizemb.to_string(),
-            extension: f.extension.to_string(),
-        }
-    }
-}
-impl From<&FileSpec> for slint_generatedApp::FileSpec {
-    fn from(f: &FileSpec) -> Self {
-        Self {
-            path: SharedString::from(f.path.clone()),
-            name: SharedString::from(f.name.clone()),
-            size: SharedString::from(format!("{}", f.size)),
-            sizemb: SharedString::from(f.sizemb.clone()),
-            extension: SharedString::from(f.extension.clone()),
-        }
-    }
-}
-
 pub fn get_file_name(file: Option<PathBuf>) -> Option<(String, String)> {
     let file = file?;
     let file_name = file.file_name()?.to_str()?.to_string();
@@ -55,255 +34,794 @@ pub fn get_file_name(file: Option<PathBuf>) -> Option<(String, String)> {
     Some((file_name, file_path))
 }
 
-/// 选择文件
-pub(crate) fn pick_file(filter: Option<(&str, &[&str])>) -> Option<FileSpec> {
-    let mut dlg = FileDialog::new();
-    if let Some((filter_name, extensions)) = filter {
-        dlg = dlg.add_filter(filter_name, extensions);
-    }
+/// 读取文件或文件夹的信息，文件夹的大小为其中所有文件的大小
+pub fn get_file_spec(file: Option<PathBuf>) -> Option<FileSpec> {
+    let (file_name, file_path) = get_file_name(file)?;
+
+    let meta = fs::metadata(&file_path).ok()?;
+    let (size, extension) = if meta.is_dir() {
+        // 只用来显示和计算进度，文件夹中有无法保存的项时保存附件时再提示
+        (
+            tree::dir_size(Path::new(&file_path)).unwrap_or(0),
+            String::new(),
+        )
+    } else {
+        (meta.len(), get_extension(&file_name))
+    };
+    Some(FileSpec {
+        path: file_path,
+        name: file_name,
+        size,
+        sizemb: get_size_str(size),
+        extension,
+    })
+}
 
-    let (file_name, file_path) = get_file_name(dlg.pick_file())?;
-
-    let extension = file_name
-        .split(".")
-        .last()
-        .unwrap_or("")
-        .to_uppercase()
-        .to_string();
-
-    if let Ok(data) = fs::metadata(&file_path) {
-        let size = data.len();
-        return Some(FileSpec {
-            path: file_path,
-            name: file_name,
-            size,
-            sizemb: get_size_str(size),
-            extension,
-        });
-    }
+/// 文件扩展名(大写)
+pub fn get_extension(file_name: &str) -> String {
+    file_name.rsplit('.').next().unwrap_or("").to_uppercase()
+}
+
+/// 两个路径是否指向同一个文件，包括符号链接和硬链接，路径不存在时为false
+pub(crate) fn is_same_file(a: &Path, b: &Path) -> bool {
+    same_file::is_same_file(a, b).unwrap_or(false)
+}
 
-    None
+/// 保存的路径不能是附件，也不能在附加的文件夹中
+pub(crate) fn check_output(output: &Path, append_file_specs: &[FileSpec]) -> Result<()> {
+    let output_dir = fs::canonicalize(parent_dir(output)).ok();
+    for spec in append_file_specs {
+        let path = Path::new(&spec.path);
+        let in_dir = match (&output_dir, fs::canonicalize(path)) {
+            (Some(output_dir), Ok(dir)) => dir.is_dir() && output_dir.starts_with(dir),
+            _ => false,
+        };
+        if is_same_file(output, path) || in_dir {
+            return Err(Error::InvalidInput(format!(
+                "保存的路径不能是附件或者在附加的文件夹中:{}",
+                output.display()
+            )));
+        }
+    }
+    Ok(())
 }
 
-/// 检测源文件中是否有附加文件
-pub fn check_file(src_file_spec: &FileSpec) -> anyhow::Result<Option<(FileSpec, u64, u64)>> {
-    let src_file = File::open(&src_file_spec.path)?;
-
-    //从文件结尾处查找开始字节
-    let file_size = src_file_spec.size;
-    let mut buf = vec![0; START_BYTES.len()];
-    let start_pos = file_size - START_BYTES.len() as u64;
-    let len = src_file.seek_read(&mut buf, start_pos)?;
-    if len != buf.len() {
-        return Ok(None);
+/// 提取的路径不能是源文件
+fn check_not_carrier(src_path: &str, output: &Path) -> Result<()> {
+    if is_same_file(Path::new(src_path), output) {
+        return Err(Error::InvalidInput(format!(
+            "不能提取到源文件:{}",
+            output.display()
+        )));
     }
-    if buf != START_BYTES.as_bytes() {
-        return Ok(None);
+    Ok(())
+}
+
+/// 文件所在的文件夹，没有时为当前文件夹
+fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
     }
-    // 字节匹配，寻找结束地址
-    let mut buf = [0; 1];
-    let mut data = vec![];
-    let mut offset = start_pos;
-    let end_bytes = END_BYTES.as_bytes();
+}
+
+/// # 原子写入文件
+///
+/// 先写入目标文件夹中的临时文件，写入成功并同步到磁盘后再替换目标文件。
+/// 失败或取消时删除临时文件，已存在的目标文件保持不变
+pub(crate) fn write_atomic<T>(
+    path: &Path,
+    write: impl FnOnce(&mut File) -> Result<T>,
+) -> Result<T> {
+    let mut temp = tempfile::Builder::new()
+        .prefix(".hidden-files-")
+        .tempfile_in(parent_dir(path))?;
+    let value = write(temp.as_file_mut())?;
+    // 覆盖已有文件时保留原来的权限。新文件保持临时文件的权限，只有所有者可以读写，
+    // 提取的可能是加密的附件，不能让其他用户读取
+    let mode = fs::metadata(path)
+        .ok()
+        .and_then(|meta| tree::file_mode(&meta));
+    tree::set_file_mode(temp.path(), EntryKind::File, mode)?;
+    temp.as_file().sync_all()?;
+    temp.persist(path).map_err(|err| err.error)?;
+    Ok(value)
+}
+
+/// 分块复制数据，返回复制的字节数
+pub(crate) fn copy_chunks<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    buf: &mut [u8],
+    chunks: &mut ChunkProgress,
+) -> Result<u64> {
+    let mut copied = 0;
     loop {
-        if src_file.seek_read(&mut buf, offset)? == 1 {
-            offset -= 1;
-            data.push(buf[0]);
+        let len = match reader.read(buf) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into()),
+        };
+        writer.write_all(&buf[0..len])?;
+        copied += len as u64;
+        chunks.advance(len)?;
+    }
+    Ok(copied)
+}
 
-            if data.len() > 4096 {
-                println!("数据太长，提前结束！");
-                break;
-            }
+/// 保存附件时的选项
+#[derive(Clone, Debug, Default)]
+pub struct EmbedOptions {
+    /// 附件加密使用的密码，为None时不加密
+    pub password: Option<String>,
+    /// 同时加密附件信息(文件名、大小等)，需要设置密码
+    pub encrypt_metadata: bool,
+    /// 保存附件的修改时间和权限，默认只保存文件名
+    pub keep_metadata: bool,
+    /// 附件压缩算法
+    pub compression: Codec,
+    /// 附件的保存方式，默认追加在源文件末尾
+    pub layout: Layout,
+}
 
-            //最后END_BYTES.len()个字节反转对比
-            if data.len() > END_BYTES.len() {
-                let mut data_end_arr = data[data.len() - END_BYTES.len()..].to_vec();
-                data_end_arr.reverse();
-                if data_end_arr == end_bytes {
-                    let mut spec_data = data[..data.len() - END_BYTES.len()].to_vec();
-                    spec_data.reverse();
-                    let (f, _u): (FileSpec, usize) =
-                        bincode::decode_from_slice(&spec_data, config::standard())?;
+/// 解压后允许的最大长度，防止恶意构造的压缩数据耗尽磁盘
+pub(crate) const DEFAULT_MAX_DECOMPRESSED_SIZE: u64 = 16 * 1024 * 1024 * 1024;
 
-                    let end_offset = offset + 1;
-                    let start_offset = end_offset - f.size;
+/// 提取附件时的选项
+#[derive(Clone, Debug)]
+pub struct ExtractOptions {
+    /// 附件加密时使用的密码
+    pub password: Option<String>,
+    /// 压缩的附件解压后允许的最大长度
+    pub max_decompressed_size: u64,
+}
 
-                    return Ok(Some((f, start_offset, end_offset)));
-                }
-            }
-        } else {
-            break;
+impl Default for ExtractOptions {
+    fn default() -> Self {
+        Self {
+            password: None,
+            max_decompressed_size: DEFAULT_MAX_DECOMPRESSED_SIZE,
         }
     }
-    Ok(None)
+}
+
+/// 检查附件名称，文件夹展开前先检查，避免写入一部分数据后才发现名称重复
+fn check_names(append_file_specs: &[FileSpec]) -> Result<()> {
+    if append_file_specs.is_empty() {
+        return Err(Error::InvalidInput("没有附加文件！".to_string()));
+    }
+    let mut names = HashSet::new();
+    for spec in append_file_specs {
+        if !names.insert(spec.name.as_str()) {
+            return Err(Error::InvalidInput(format!("附件名称重复:{}", spec.name)));
+        }
+    }
+    Ok(())
+}
+
+/// 依次写入全部附件，头部和尾部由调用者写入
+fn add_attachments<W: Write>(
+    embedder: &mut Embedder<W>,
+    append_file_specs: &[FileSpec],
+    chunks: &mut ChunkProgress,
+) -> Result<()> {
+    for append_file_spec in append_file_specs {
+        embedder.add_path(
+            Path::new(&append_file_spec.path),
+            &append_file_spec.name,
+            chunks,
+        )?;
+    }
+    Ok(())
 }
 
 /// # 保存文件和附件
 ///
+/// 源文件中已有附件时只复制原始源文件部分，已有的附件被替换
+///
 /// 参数:
 /// * `src_file_spec`: 源文件信息
-/// * `append_file_spec`: 附加文件信息
-/// * `output_file_name`: 合并后保存的路径
-/// * `progress_callback`: 进度回调函数
-/// * `is_cancled`: 读写锁，用来检测操作是否取消
-pub(crate) fn copy_file<F: Fn(i32)>(
+/// * `existing`: 源文件中已有的附件信息
+/// * `append_file_specs`: 附加文件列表，可以是文件夹
+/// * `output_file_name`: 合并后保存的路径，可以是源文件
+/// * `options`: 加密、压缩等选项
+/// * `progress`: 接收进度事件
+/// * `cancel`: 取消令牌，用来检测操作是否取消
+pub(crate) fn copy_file(
     src_file_spec: &FileSpec,
-    append_file_spec: &FileSpec,
+    existing: Option<&Attachments>,
+    append_file_specs: &[FileSpec],
     output_file_name: &str,
-    progress_callback: F,
-    is_cancled: Arc<RwLock<bool>>,
-) -> anyhow::Result<()> {
-    let mut append_file_spec = append_file_spec.clone();
-    let mut output_file = File::create(&output_file_name)?;
-
+    options: &EmbedOptions,
+    progress: &dyn Progress,
+    cancel: &CancelToken,
+) -> Result<()> {
+    if existing.is_some_and(|existing| existing.sealed) {
+        return Err(Error::PasswordRequired);
+    }
+    check_names(append_file_specs)?;
+    let src_layout = existing.map_or(Layout::Append, |existing| existing.layout);
+    // 只用来检查是否取消，扫描源文件时不通知进度
+    let mut scan = ChunkProgress::new(Phase::Carrier, 0, progress, cancel);
     let mut src_file = File::open(&src_file_spec.path)?;
-    let mut append_file = File::open(&append_file_spec.path)?;
-
-    //文件结构：源文件字节 附加文件字节 RUSTAPPEND666E FileSpec RUSTAPPEND666S
-    let total = src_file_spec.size + append_file_spec.size;
-    println!(
-        "源文件:{} 附加文件:{} 总大小:{}",
-        get_size_str(src_file_spec.size),
-        get_size_str(append_file_spec.size),
-        get_size_str(total)
+    if existing.is_some() {
+        layout::check_restore(&mut src_file, src_layout, &mut scan)?;
+    }
+    let (_, src_file) = layout::open(src_file, Some(src_layout), &mut scan)?;
+    let mut carrier = match existing {
+        Some(existing) => src_file.limit(existing.data_start),
+        None => src_file,
+    };
+    let carrier_len = carrier.seek(SeekFrom::End(0))?;
+    carrier.rewind()?;
+
+    let append_size: u64 = append_file_specs.iter().map(|spec| spec.size).sum();
+    let total = carrier_len + append_size;
+    log::debug!(
+        "源文件:{} 附加文件:{}个 {} 总大小:{} 保存方式:{:?}",
+        get_size_str(carrier_len),
+        append_file_specs.len(),
+        get_size_str(append_size),
+        get_size_str(total),
+        options.layout
     );
 
-    let mut current = 0;
-    let mut total_chunks = 0;
-    let mut buf = [0; 1024 * 1024];
-    loop {
-        let len = src_file.read(&mut buf)?;
-        if len == 0 {
-            break;
-        }
-        output_file.write_all(&buf[0..len])?;
-        current += len;
-        total_chunks += 1;
-
-        // 每10MB通知进度，并检查是否取消当前操作
-        if let (true, Ok(canceled)) = (total_chunks % 10 == 0, is_cancled.read()) {
-            let progress = ((current as f64 / total as f64) * 100.) as i32;
-            progress_callback(progress);
-            // println!("current={} total={} progress={progress}", get_size_str(current as u64), get_size_str(total));
-            if *canceled {
-                return Err(anyhow!("操作取消！"));
+    let mut chunks = ChunkProgress::new(Phase::Carrier, total, progress, cancel);
+    //文件结构：源文件字节 附件1字节 附件2字节 ... 头部(Manifest或SealedHeader) 尾部(Footer)，
+    //不是追加在末尾时附件、头部和尾部分块插入到源文件中
+    write_atomic(Path::new(output_file_name), |output_file| {
+        layout::embed(
+            &mut carrier,
+            output_file,
+            options,
+            &mut chunks,
+            |embedder, chunks| add_attachments(embedder, append_file_specs, chunks),
+        )?;
+        Ok(())
+    })?;
+
+    chunks.finish();
+    Ok(())
+}
+
+/// # 直接在源文件末尾追加附件，不复制源文件
+///
+/// 不会读取源文件，所以不保存原始源文件的校验值。失败或取消时把源文件截断为原来的长度
+///
+/// 参数:
+/// * `src_file_spec`: 源文件信息
+/// * `append_file_specs`: 附加文件列表，可以是文件夹
+/// * `options`: 加密、压缩等选项
+/// * `progress`: 接收进度事件
+/// * `cancel`: 取消令牌，用来检测操作是否取消
+pub(crate) fn append_file(
+    src_file_spec: &FileSpec,
+    append_file_specs: &[FileSpec],
+    options: &EmbedOptions,
+    progress: &dyn Progress,
+    cancel: &CancelToken,
+) -> Result<()> {
+    check_names(append_file_specs)?;
+
+    // 以写入方式打开并定位到末尾，截断文件需要写权限
+    let mut src_file = OpenOptions::new().write(true).open(&src_file_spec.path)?;
+    let original_len = src_file.seek(SeekFrom::End(0))?;
+
+    let append_size: u64 = append_file_specs.iter().map(|spec| spec.size).sum();
+    log::debug!(
+        "源文件:{} 追加附件:{}个 {}",
+        get_size_str(original_len),
+        append_file_specs.len(),
+        get_size_str(append_size)
+    );
+
+    let mut chunks = ChunkProgress::new(Phase::Embed, append_size, progress, cancel);
+    write_in_place(
+        &mut src_file,
+        original_len,
+        append_file_specs,
+        options,
+        None,
+        None,
+        &mut chunks,
+    )?;
+
+    chunks.finish();
+    Ok(())
+}
+
+/// 从`start`位置开始覆盖写入附件并删除后面剩余的旧数据。
+/// 失败或取消时把文件截断为`start`，再写回`backup`中保存的旧附件
+fn write_in_place(
+    file: &mut File,
+    start: u64,
+    append_file_specs: &[FileSpec],
+    options: &EmbedOptions,
+    carrier_sha256: Option<[u8; crypto::HASH_LEN]>,
+    backup: Option<&mut File>,
+    chunks: &mut ChunkProgress,
+) -> Result<()> {
+    file.seek(SeekFrom::Start(start))?;
+    let res = Embedder::new(&mut *file, options)
+        .and_then(|mut embedder| {
+            embedder.set_carrier_sha256(carrier_sha256);
+            add_attachments(&mut embedder, append_file_specs, chunks)?;
+            embedder.finish()
+        })
+        .and_then(|file| {
+            // 新附件比旧附件短时，截断剩余的旧数据
+            let end = file.stream_position()?;
+            file.set_len(end)?;
+            Ok(file.sync_all()?)
+        });
+    if let Err(err) = res {
+        log::debug!("写入附件失败，还原源文件长度:{start}");
+        // 还原失败时仍然返回原来的错误，并提示源文件可能已经损坏
+        return match restore_backup(file, start, backup) {
+            Ok(()) => Err(err),
+            Err(restore_err) => {
+                log::error!("还原旧附件失败:{restore_err}");
+                let err = match err {
+                    Error::Io(err) => err.to_string(),
+                    err => err.to_string(),
+                };
+                Err(Error::Io(io::Error::other(format!(
+                    "{}，还原旧附件也失败了:{}，源文件可能已经损坏！",
+                    err, restore_err
+                ))))
             }
-        }
-        if len < buf.len() {
-            break;
-        }
+        };
     }
-    let mut append_total_size = 0;
-    loop {
-        let len = append_file.read(&mut buf)?;
-        if len == 0 {
-            break;
-        }
-        append_total_size += len;
-        output_file.write_all(&buf[0..len])?;
-        current += len;
-        total_chunks += 1;
-
-        // 每10MB通知进度，并检查是否取消当前操作
-        if let (true, Ok(canceled)) = (total_chunks % 10 == 0, is_cancled.read()) {
-            let progress = ((current as f64 / total as f64) * 100.) as i32;
-            // println!("current={} total={} progress={progress}", get_size_str(current as u64), get_size_str(total));
-            progress_callback(progress);
-            if *canceled {
-                return Err(anyhow!("操作取消！"));
-            }
-        }
+    Ok(())
+}
 
-        if len < buf.len() {
-            break;
-        }
+/// 把文件截断为`start`，再写回`backup`中保存的旧附件
+fn restore_backup(file: &mut File, start: u64, backup: Option<&mut File>) -> io::Result<()> {
+    file.set_len(start)?;
+    if let Some(backup) = backup {
+        backup.rewind()?;
+        file.seek(SeekFrom::Start(start))?;
+        io::copy(backup, file)?;
+        file.sync_all()?;
     }
+    Ok(())
+}
 
-    append_file_spec.size = append_total_size as u64;
-    let append_file_spec_data = bincode::encode_to_vec(&append_file_spec, config::standard())?;
-
-    output_file.write_all(END_BYTES.as_bytes())?;
-    output_file.write_all(&append_file_spec_data)?;
-    output_file.write_all(START_BYTES.as_bytes())?;
+/// # 直接修改源文件，替换已有的附件
+///
+/// 保留原始源文件部分，从附件区开始位置覆盖写入新的附件，只支持追加在末尾的保存方式。
+/// 旧附件先复制到源文件所在文件夹的临时文件中，失败或取消时写回源文件
+///
+/// 参数:
+/// * `src_file_spec`: 源文件信息
+/// * `attachments`: `check_file`读取到的已有附件信息
+/// * `append_file_specs`: 新的附加文件列表，可以是文件夹
+/// * `options`: 加密、压缩等选项
+/// * `progress`: 接收进度事件
+/// * `cancel`: 取消令牌，用来检测操作是否取消
+pub(crate) fn update_file(
+    src_file_spec: &FileSpec,
+    attachments: &Attachments,
+    append_file_specs: &[FileSpec],
+    options: &EmbedOptions,
+    progress: &dyn Progress,
+    cancel: &CancelToken,
+) -> Result<()> {
+    if attachments.sealed {
+        return Err(Error::PasswordRequired);
+    }
+    check_names(append_file_specs)?;
+
+    let mut src_file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&src_file_spec.path)?;
+    let old_len = src_file.seek(SeekFrom::End(0))? - attachments.data_start;
+    let append_size: u64 = append_file_specs.iter().map(|spec| spec.size).sum();
+    log::debug!(
+        "源文件:{} 替换附件:{}个 {} -> {}个 {}",
+        get_size_str(attachments.data_start),
+        attachments.entries.len(),
+        get_size_str(old_len),
+        append_file_specs.len(),
+        get_size_str(append_size)
+    );
 
-    progress_callback(100);
+    // 备份旧附件时源文件还没有修改，失败或取消时直接返回
+    let mut chunks = ChunkProgress::new(Phase::Carrier, old_len + append_size, progress, cancel);
+    let mut backup = tempfile::tempfile_in(parent_dir(Path::new(&src_file_spec.path)))?;
+    src_file.seek(SeekFrom::Start(attachments.data_start))?;
+    copy_chunks(
+        &mut src_file,
+        &mut backup,
+        &mut vec![0; BUF_SIZE],
+        &mut chunks,
+    )?;
+
+    // 原始源文件没有变化，继续使用原来的校验值
+    chunks.set_phase(Phase::Embed);
+    write_in_place(
+        &mut src_file,
+        attachments.data_start,
+        append_file_specs,
+        options,
+        attachments.carrier_sha256,
+        Some(&mut backup),
+        &mut chunks,
+    )?;
+    chunks.finish();
     Ok(())
 }
 
-fn get_size_str(size: u64) -> String {
+pub fn get_size_str(size: u64) -> String {
     Byte::from_bytes(size as u128)
         .get_appropriate_unit(false)
         .to_string()
 }
 
-/// # 保存文件和附件
+/// 打开源文件，使用已经读取到的附件信息
+fn open_extractor(
+    src_path: &str,
+    attachments: &Attachments,
+    options: &ExtractOptions,
+) -> Result<Extractor<File>> {
+    let src_file = File::open(src_path)?;
+    log::debug!("源文件信息 大小:{}", src_file.metadata()?.len());
+    Extractor::with_attachments(src_file, attachments.clone(), options)
+}
+
+/// # 提取一个附件
 ///
 /// 参数:
 /// * `src_path`: 源文件路径
+/// * `attachments`: `check_file`读取到的附件信息
+/// * `entry`: 要提取的附件
 /// * `output_file`: 提取到的路径
-/// * `start_offset`: 开始的字节位置
-/// * `end_offset`: 结束的字节位置
-/// * `progress_callback`: 进度回调函数
-/// * `is_cancled`: 读写锁，用来检测操作是否取消
-pub(crate) fn extract_file<F: Fn(i32)>(
+/// * `options`: 密码、解压大小限制等选项
+/// * `progress`: 接收进度事件
+/// * `cancel`: 取消令牌，用来检测操作是否取消
+pub(crate) fn extract_file(
     src_path: &str,
+    attachments: &Attachments,
+    entry: &Entry,
     output_file: &str,
-    start_offset: u64,
-    end_offset: u64,
-    progress_callback: F,
-    is_cancled: Arc<RwLock<bool>>,
-) -> anyhow::Result<()> {
-    let mut output_file = File::create(&output_file)?;
-
-    let meta = fs::metadata(&src_path)?;
-    println!("源文件信息 大小:{}", meta.file_size());
-
-    let src_file = File::open(&src_path)?;
-
-    let total = end_offset - start_offset;
-
-    let mut current = 0;
-    let mut total_chunks = 0;
-    let mut buf = [0; 1024 * 1024];
-    let mut offset = start_offset;
-    println!(
-        "开始提取附件start_offset={offset} end_offset={}",
-        end_offset
-    );
-    loop {
-        let mut len = src_file.seek_read(&mut buf, offset)?;
-        if len == 0 {
-            println!("读到的字节为0!");
-            break;
+    options: &ExtractOptions,
+    progress: &dyn Progress,
+    cancel: &CancelToken,
+) -> Result<()> {
+    check_not_carrier(src_path, Path::new(output_file))?;
+    let mut extractor = open_extractor(src_path, attachments, options)?;
+    let mut chunks = ChunkProgress::new(Phase::Extract, entry.size, progress, cancel);
+    write_atomic(Path::new(output_file), |file| {
+        extractor.extract_with(entry, file, &mut chunks)
+    })?;
+    tree::set_file_mtime(Path::new(output_file), EntryKind::File, entry.mtime)?;
+    tree::set_file_mode(Path::new(output_file), EntryKind::File, entry.mode)?;
+
+    chunks.finish();
+    Ok(())
+}
+
+/// # 提取全部附件到指定文件夹，文件夹中的附件保持原来的目录结构
+///
+/// 参数:
+/// * `src_path`: 源文件路径
+/// * `attachments`: `check_file`读取到的附件信息
+/// * `output_dir`: 保存附件的文件夹
+/// * `options`: 密码、解压大小限制等选项
+/// * `progress`: 接收进度事件
+/// * `cancel`: 取消令牌，用来检测操作是否取消
+pub(crate) fn extract_all(
+    src_path: &str,
+    attachments: &Attachments,
+    output_dir: &str,
+    options: &ExtractOptions,
+    progress: &dyn Progress,
+    cancel: &CancelToken,
+) -> Result<()> {
+    let mut extractor = open_extractor(src_path, attachments, options)?;
+    let total = attachments.entries.iter().map(|e| e.size).sum();
+
+    let mut chunks = ChunkProgress::new(Phase::Extract, total, progress, cancel);
+    let mut dirs = vec![];
+    for entry in &attachments.entries {
+        let output_path = tree::output_path(Path::new(output_dir), &entry.name)?;
+        match entry.kind {
+            EntryKind::File => {
+                if let Some(parent) = output_path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                check_not_carrier(src_path, &output_path)?;
+                write_atomic(&output_path, |file| {
+                    extractor.extract_with(entry, file, &mut chunks)
+                })?;
+                tree::set_file_mtime(&output_path, EntryKind::File, entry.mtime)?;
+                tree::set_file_mode(&output_path, EntryKind::File, entry.mode)?;
+            }
+            EntryKind::Dir => {
+                fs::create_dir_all(&output_path)?;
+                dirs.push((output_path, entry.mode, entry.mtime));
+            }
         }
-        if current + len <= total as usize {
-            // println!("写入了{:?}", &buf[0..len]);
-            output_file.write_all(&buf[0..len])?;
-        } else {
-            len = total as usize - current;
-            // println!("写入了{:?}", &buf[0..len]);
-            output_file.write_all(&buf[0..len])?;
+    }
+    // 文件全部写入之后再设置文件夹的修改时间和权限，避免写入文件时修改时间改变、只读文件夹无法写入
+    for (dir, mode, mtime) in dirs.into_iter().rev() {
+        tree::set_file_mtime(&dir, EntryKind::Dir, mtime)?;
+        tree::set_file_mode(&dir, EntryKind::Dir, mode)?;
+    }
+
+    chunks.finish();
+    Ok(())
+}
+
+/// 附件的校验结果
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VerifyStatus {
+    /// 数据完整
+    Ok,
+    /// 旧格式的附件没有校验值，无法校验
+    Unchecked,
+    /// 数据已损坏
+    Corrupted,
+}
+
+/// # 校验全部附件，不写入任何文件
+///
+/// 只计算附件在源文件中的数据的校验值，不需要解密，附件加密时也不需要密码
+///
+/// 参数:
+/// * `src_path`: 源文件路径
+/// * `attachments`: `check_file`读取到的附件信息
+/// * `progress`: 接收进度事件
+/// * `cancel`: 取消令牌，用来检测操作是否取消
+pub(crate) fn verify(
+    src_path: &str,
+    attachments: &Attachments,
+    progress: &dyn Progress,
+    cancel: &CancelToken,
+) -> Result<Vec<(String, VerifyStatus)>> {
+    let mut extractor = open_extractor(src_path, attachments, &ExtractOptions::default())?;
+    let total = attachments.entries.iter().map(|e| e.stored_len).sum();
+
+    let mut chunks = ChunkProgress::new(Phase::Verify, total, progress, cancel);
+    let results = extractor.verify_with(&mut chunks)?;
+
+    chunks.finish();
+    Ok(results)
+}
+
+/// # 移除附件，把源文件还原为附加附件之前的内容
+///
+/// 保存了原始源文件的校验值时先校验，校验失败不会修改源文件
+///
+/// 参数:
+/// * `src_path`: 源文件路径
+/// * `attachments`: `check_file`读取到的附件信息
+/// * `output_file`: 还原后保存的路径，None时直接截断源文件
+/// * `progress`: 接收进度事件
+/// * `cancel`: 取消令牌，用来检测操作是否取消
+pub(crate) fn strip(
+    src_path: &str,
+    attachments: &Attachments,
+    output_file: Option<&str>,
+    progress: &dyn Progress,
+    cancel: &CancelToken,
+) -> Result<()> {
+    let mut chunks = ChunkProgress::new(Phase::Strip, attachments.data_start, progress, cancel);
+    layout::check_restore(&mut File::open(src_path)?, attachments.layout, &mut chunks)?;
+    let mut extractor = open_extractor(src_path, attachments, &ExtractOptions::default())?;
+    log::debug!("移除附件 原始文件长度:{}", attachments.data_start);
+
+    // 不是追加在末尾时不能直接截断，需要重写源文件
+    let output_file = output_file.or((attachments.layout != Layout::Append).then_some(src_path));
+    match output_file {
+        Some(output_file) => {
+            write_atomic(Path::new(output_file), |file| {
+                extractor.carrier_with(file, &mut chunks)
+            })?;
         }
-        current += len;
-        total_chunks += 1;
-        offset += len as u64;
-
-        // 每10MB通知进度，并检查是否取消当前操作
-        if let (true, Ok(canceled)) = (total_chunks % 10 == 0, is_cancled.read()) {
-            let progress = ((current as f64 / total as f64) * 100.) as i32;
-            // println!("current={} total={} progress={progress}", get_size_str(current as u64), get_size_str(total));
-            progress_callback(progress);
-            if *canceled {
-                return Err(anyhow!("操作取消！"));
+        None => {
+            if attachments.carrier_sha256.is_some() {
+                extractor.carrier_with(&mut io::sink(), &mut chunks)?;
             }
-        }
-        if len < buf.len() {
-            println!("读取长度小于缓冲区长度！");
-            break;
+            drop(extractor);
+            OpenOptions::new()
+                .write(true)
+                .open(src_path)?
+                .set_len(attachments.data_start)?;
         }
     }
-    println!("文件提取结束 截止offset={offset} 写入长度:{current}");
 
-    progress_callback(100);
+    chunks.finish();
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::*;
+    use crate::progress::ProgressEvent;
+
+    /// 在源文件末尾保存`old.txt`，返回源文件路径和保存后的内容
+    fn carrier_with_attachment(dir: &Path) -> (PathBuf, Vec<u8>) {
+        let carrier = dir.join("carrier.bin");
+        fs::write(&carrier, vec![7; 6000]).unwrap();
+        let old = dir.join("old.txt");
+        fs::write(&old, "old attachment").unwrap();
+        crate::embed(
+            &carrier,
+            &[old],
+            None,
+            &EmbedOptions::default(),
+            &crate::NoProgress,
+            &CancelToken::new(),
+        )
+        .unwrap();
+        let data = fs::read(&carrier).unwrap();
+        (carrier, data)
+    }
+
+    /// 源文件中仍然是原来的附件
+    fn assert_old_attachment(carrier: &Path, data: &[u8]) {
+        assert_eq!(fs::read(carrier).unwrap(), data);
+        let cancel = CancelToken::new();
+        let attachments = crate::inspect(carrier, None, &crate::NoProgress, &cancel)
+            .unwrap()
+            .unwrap();
+        assert_eq!(attachments.entries.len(), 1);
+        assert_eq!(attachments.entries[0].name, "old.txt");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn new_file_owner_only() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("secret.txt");
+        write_atomic(&path, |file| Ok(file.write_all(b"secret")?)).unwrap();
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&path), 0o600);
+        // 覆盖已有文件时保留原来的权限
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        write_atomic(&path, |file| Ok(file.write_all(b"new")?)).unwrap();
+        assert_eq!(mode(&path), 0o640);
+    }
+
+    #[test]
+    fn extract_all_restores_dir_mtime() {
+        let dir = tempfile::tempdir().unwrap();
+        let docs = dir.path().join("docs");
+        fs::create_dir_all(docs.join("sub")).unwrap();
+        fs::write(docs.join("sub/a.txt"), "a").unwrap();
+        let old = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        for path in [docs.join("sub/a.txt"), docs.join("sub"), docs.clone()] {
+            File::open(&path).unwrap().set_modified(old).unwrap();
+        }
+        let carrier = dir.path().join("carrier.bin");
+        fs::write(&carrier, vec![7; 100]).unwrap();
+        let options = EmbedOptions {
+            keep_metadata: true,
+            ..Default::default()
+        };
+        let cancel = CancelToken::new();
+        crate::embed(
+            &carrier,
+            &[docs],
+            None,
+            &options,
+            &crate::NoProgress,
+            &cancel,
+        )
+        .unwrap();
+
+        let attachments = crate::inspect(&carrier, None, &crate::NoProgress, &cancel)
+            .unwrap()
+            .unwrap();
+        let output = dir.path().join("out");
+        let options = ExtractOptions::default();
+        crate::extract_all(
+            &carrier,
+            &attachments,
+            &output,
+            &options,
+            &crate::NoProgress,
+            &cancel,
+        )
+        .unwrap();
+        for path in ["docs", "docs/sub", "docs/sub/a.txt"] {
+            let mtime = fs::metadata(output.join(path)).unwrap().modified().unwrap();
+            assert_eq!(mtime, old, "{}", path);
+        }
+    }
+
+    #[test]
+    fn cancel_replace_keeps_old_attachment() {
+        let dir = tempfile::tempdir().unwrap();
+        let (carrier, data) = carrier_with_attachment(dir.path());
+        let big = dir.path().join("big.bin");
+        fs::write(&big, vec![1; 5 * 1024 * 1024]).unwrap();
+
+        // 开始写入新附件后取消
+        let cancel = CancelToken::new();
+        let progress = |event: &ProgressEvent| {
+            if event.phase == Phase::Embed {
+                cancel.cancel();
+            }
+        };
+        let res = crate::embed(
+            &carrier,
+            &[big],
+            None,
+            &EmbedOptions::default(),
+            &progress,
+            &cancel,
+        );
+        assert!(matches!(res, Err(Error::Cancelled)));
+        assert_old_attachment(&carrier, &data);
+    }
+
+    #[test]
+    fn failed_replace_keeps_old_attachment() {
+        let dir = tempfile::tempdir().unwrap();
+        let (carrier, data) = carrier_with_attachment(dir.path());
+        let cancel = CancelToken::new();
+        let attachments = crate::inspect(&carrier, None, &crate::NoProgress, &cancel)
+            .unwrap()
+            .unwrap();
+        let new = dir.path().join("new.txt");
+        fs::write(&new, "new attachment").unwrap();
+
+        // 第一个附件写入之后，第二个附件已经不存在
+        let specs = [
+            get_file_spec(Some(new)).unwrap(),
+            FileSpec {
+                path: dir.path().join("missing.txt").to_str().unwrap().to_string(),
+                name: "missing.txt".to_string(),
+                ..Default::default()
+            },
+        ];
+        let res = update_file(
+            &get_file_spec(Some(carrier.clone())).unwrap(),
+            &attachments,
+            &specs,
+            &EmbedOptions::default(),
+            &crate::NoProgress,
+            &cancel,
+        );
+        assert!(matches!(res, Err(Error::Io(_))));
+        assert_old_attachment(&carrier, &data);
+    }
+
+    #[test]
+    fn failed_restore_keeps_original_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let (carrier, _) = carrier_with_attachment(dir.path());
+        let start = fs::metadata(&carrier).unwrap().len();
+        let mut file = File::options().write(true).open(&carrier).unwrap();
+        // 备份文件只能写入，写回旧附件时读取失败
+        let backup_path = dir.path().join("backup.bin");
+        fs::write(&backup_path, "old attachment").unwrap();
+        let mut backup = File::options().write(true).open(&backup_path).unwrap();
+        let specs = [FileSpec {
+            path: dir.path().join("missing.txt").to_str().unwrap().to_string(),
+            name: "missing.txt".to_string(),
+            ..Default::default()
+        }];
+        let cancel = CancelToken::new();
+        let mut chunks = ChunkProgress::new(Phase::Embed, 0, &crate::NoProgress, &cancel);
+        let res = write_in_place(
+            &mut file,
+            start,
+            &specs,
+            &EmbedOptions::default(),
+            None,
+            Some(&mut backup),
+            &mut chunks,
+        );
+        let msg = res.err().unwrap().to_string();
+        assert!(msg.contains("还原旧附件也失败了"), "{}", msg);
+        assert!(!msg.contains("读写文件失败:读写文件失败"), "{}", msg);
+    }
+}
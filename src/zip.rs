@@ -0,0 +1,446 @@
+use std::io::{self, SeekFrom, Write};
+
+use crate::{
+    error::{Error, Result},
+    format::ReadSeek,
+    layout::{Patches, Segments},
+    progress::ChunkProgress,
+};
+
+pub(crate) const EOCD_MAGIC: &[u8] = b"PK\x05\x06";
+/// 目录结束记录的固定长度，后面是最长65535字节的注释
+const EOCD_LEN: u64 = 22;
+const CENTRAL_MAGIC: &[u8] = b"PK\x01\x02";
+const ZIP64_LOCATOR_MAGIC: &[u8] = b"PK\x06\x07";
+const ZIP64_LOCATOR_LEN: u64 = 20;
+const ZIP64_EOCD_MAGIC: &[u8] = b"PK\x06\x06";
+/// ZIP64目录结束记录的固定长度，不包括扩展数据
+const ZIP64_EOCD_LEN: u64 = 56;
+/// 附件数据之后的标识，前面是8字节的附件数据长度
+const MARKER: &[u8; 8] = b"hiDnZIP\0";
+const MARKER_LEN: u64 = 16;
+
+fn read_array<const N: usize>(reader: &mut dyn ReadSeek, offset: u64) -> io::Result<[u8; N]> {
+    let mut buf = [0; N];
+    reader.seek(SeekFrom::Start(offset))?;
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn le_u16(data: &[u8], pos: usize) -> u16 {
+    u16::from_le_bytes([data[pos], data[pos + 1]])
+}
+
+fn le_u32(data: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap())
+}
+
+fn le_u64(data: &[u8], pos: usize) -> u64 {
+    u64::from_le_bytes(data[pos..pos + 8].try_into().unwrap())
+}
+
+/// 在末尾查找目录结束记录，返回记录位置和注释结束位置
+pub(crate) fn find_eocd(reader: &mut dyn ReadSeek, len: u64) -> io::Result<Option<(u64, u64)>> {
+    let search_len = len.min(EOCD_LEN + u16::MAX as u64);
+    let start = len - search_len;
+    let mut tail = vec![0; search_len as usize];
+    reader.seek(SeekFrom::Start(start))?;
+    reader.read_exact(&mut tail)?;
+    // 从后向前查找，注释中可能包含相同的字节
+    let eocd = (0..tail.len().saturating_sub(EOCD_LEN as usize - 1))
+        .rev()
+        .filter(|&pos| tail[pos..].starts_with(EOCD_MAGIC))
+        .map(|pos| {
+            let comment_len = le_u16(&tail, pos + 20) as u64;
+            (
+                start + pos as u64,
+                start + pos as u64 + EOCD_LEN + comment_len,
+            )
+        })
+        .find(|&(_, end)| end <= len);
+    Ok(eocd)
+}
+
+/// ZIP中央目录和目录结束记录的位置
+pub(crate) struct Directory {
+    /// 中央目录的实际开始位置，附件插入到这里
+    cd_start: u64,
+    /// 中央目录之后第一个需要修改偏移的记录，ZIP64时为ZIP64目录结束记录
+    end_start: u64,
+    eocd: u64,
+    /// ZIP64目录结束记录和定位记录的位置
+    zip64: Option<(u64, u64)>,
+}
+
+impl Directory {
+    /// # 查找中央目录
+    ///
+    /// 中央目录的位置按照目录结束记录的位置和目录长度计算，
+    /// 文件开头有其他数据时记录中的偏移可能不准确。不是ZIP文件、分卷压缩或者结构错误时返回None
+    fn find(reader: &mut dyn ReadSeek, len: u64) -> io::Result<Option<Self>> {
+        let eocd = match find_eocd(reader, len)? {
+            Some((eocd, _)) => eocd,
+            None => return Ok(None),
+        };
+        let record: [u8; EOCD_LEN as usize] = read_array(reader, eocd)?;
+        // 当前分卷号和中央目录所在的分卷号
+        if le_u16(&record, 4) != 0 || le_u16(&record, 6) != 0 {
+            return Ok(None);
+        }
+        let zip64 = if le_u16(&record, 10) == u16::MAX
+            || le_u32(&record, 12) == u32::MAX
+            || le_u32(&record, 16) == u32::MAX
+        {
+            match Self::find_zip64(reader, eocd)? {
+                Some(zip64) => Some(zip64),
+                None => return Ok(None),
+            }
+        } else {
+            None
+        };
+        let (end_start, cd_size) = match zip64 {
+            Some((zip64_eocd, _)) => {
+                let record: [u8; ZIP64_EOCD_LEN as usize] = read_array(reader, zip64_eocd)?;
+                (zip64_eocd, le_u64(&record, 40))
+            }
+            None => (eocd, le_u32(&record, 12) as u64),
+        };
+        let cd_start = match end_start.checked_sub(cd_size) {
+            Some(cd_start) => cd_start,
+            None => return Ok(None),
+        };
+        if cd_size > 0 && read_array::<4>(reader, cd_start)? != CENTRAL_MAGIC {
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            cd_start,
+            end_start,
+            eocd,
+            zip64,
+        }))
+    }
+
+    /// 查找ZIP64定位记录和目录结束记录，返回两者的位置
+    fn find_zip64(reader: &mut dyn ReadSeek, eocd: u64) -> io::Result<Option<(u64, u64)>> {
+        let locator = match eocd.checked_sub(ZIP64_LOCATOR_LEN) {
+            Some(locator) => locator,
+            None => return Ok(None),
+        };
+        let record: [u8; ZIP64_LOCATOR_LEN as usize] = read_array(reader, locator)?;
+        if &record[..4] != ZIP64_LOCATOR_MAGIC || le_u32(&record, 4) != 0 {
+            return Ok(None);
+        }
+        // 文件开头有其他数据时记录的位置不准确，ZIP64目录结束记录通常紧挨着定位记录
+        let candidates = [
+            Some(le_u64(&record, 8)),
+            locator.checked_sub(ZIP64_EOCD_LEN),
+        ];
+        for zip64_eocd in candidates.into_iter().flatten() {
+            if zip64_eocd
+                .checked_add(ZIP64_EOCD_LEN)
+                .is_some_and(|end| end <= locator)
+                && read_array::<4>(reader, zip64_eocd)? == ZIP64_EOCD_MAGIC
+            {
+                return Ok(Some((zip64_eocd, locator)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// 读取源文件的中央目录，源文件不是ZIP时返回错误
+    pub fn read(reader: &mut dyn ReadSeek, len: u64) -> Result<Self> {
+        Self::find(reader, len)?
+            .ok_or_else(|| Error::InvalidInput("源文件不是有效的ZIP文件！".to_string()))
+    }
+
+    /// 附件插入的位置，即中央目录的开始位置
+    pub fn insert_point(&self) -> u64 {
+        self.cd_start
+    }
+
+    /// 需要修改偏移的记录的开始位置，之前的中央目录直接复制
+    pub fn end_start(&self) -> u64 {
+        self.end_start
+    }
+
+    /// 在附件数据之后写入长度和标识
+    pub fn write_marker(&self, output: &mut dyn Write, data_len: u64) -> io::Result<()> {
+        output.write_all(&data_len.to_le_bytes())?;
+        output.write_all(MARKER)
+    }
+
+    /// 需要修改的偏移字段：位置、长度(4或8字节)
+    fn offset_fields(&self, record: &[u8]) -> Vec<(u64, usize)> {
+        let mut fields = vec![];
+        if let Some((zip64_eocd, locator)) = self.zip64 {
+            // ZIP64目录结束记录中的中央目录偏移，定位记录中的ZIP64目录结束记录偏移
+            fields.push((zip64_eocd + 48, 8));
+            fields.push((locator + 8, 8));
+        }
+        // 使用ZIP64时目录结束记录中的偏移为0xFFFFFFFF，不需要修改
+        if le_u32(record, 16) != u32::MAX {
+            fields.push((self.eocd + 16, 4));
+        }
+        fields
+    }
+
+    /// # 写入中央目录之后的记录
+    ///
+    /// 中央目录向后移动了附件数据和标识的长度，记录中的偏移都加上这个长度
+    ///
+    /// 参数:
+    /// * `reader`: 源文件
+    /// * `output`: 输出
+    /// * `data_len`: 附件、头部和尾部的总长度
+    pub fn write_end(
+        &self,
+        reader: &mut dyn ReadSeek,
+        output: &mut dyn Write,
+        data_len: u64,
+    ) -> Result<()> {
+        let too_large = || Error::InvalidInput("附件太大，源文件需要使用ZIP64格式！".to_string());
+        let shift = data_len.checked_add(MARKER_LEN).ok_or_else(too_large)?;
+        let end = self.eocd + EOCD_LEN;
+        let mut records = vec![0; (end - self.end_start) as usize];
+        reader.seek(SeekFrom::Start(self.end_start))?;
+        reader.read_exact(&mut records)?;
+        let eocd = (self.eocd - self.end_start) as usize;
+        for (pos, width) in self.offset_fields(&records[eocd..]) {
+            let field = &mut records[(pos - self.end_start) as usize..][..width];
+            let offset = match width {
+                4 => le_u32(field, 0) as u64,
+                _ => le_u64(field, 0),
+            };
+            let offset = offset.checked_add(shift).ok_or_else(too_large)?;
+            match width {
+                4 if offset >= u32::MAX as u64 => return Err(too_large()),
+                4 => field.copy_from_slice(&(offset as u32).to_le_bytes()),
+                _ => field.copy_from_slice(&offset.to_le_bytes()),
+            }
+        }
+        output.write_all(&records)?;
+        // 注释和之后的数据不变
+        io::copy(reader, output)?;
+        Ok(())
+    }
+}
+
+/// # 查找保存在中央目录之前的附件
+///
+/// 返回去掉附件之后的原始源文件各段、附件数据，以及还原中央目录之后各记录中偏移的修改，
+/// 没有附件时返回None
+pub(crate) fn hidden_segments(
+    reader: &mut dyn ReadSeek,
+    len: u64,
+    chunks: &mut ChunkProgress,
+) -> Result<Option<(Segments, Segments, Patches)>> {
+    // 只读取末尾的几个记录，不需要通知进度
+    chunks.check()?;
+    let directory = match Directory::find(reader, len)? {
+        Some(directory) if directory.cd_start >= MARKER_LEN => directory,
+        _ => return Ok(None),
+    };
+    let marker: [u8; MARKER_LEN as usize] = read_array(reader, directory.cd_start - MARKER_LEN)?;
+    if &marker[8..] != MARKER {
+        return Ok(None);
+    }
+    let corrupt = || Error::CorruptTrailer("ZIP中的附件长度错误！".to_string());
+    let data_len = le_u64(&marker, 0);
+    let shift = data_len.checked_add(MARKER_LEN).ok_or_else(corrupt)?;
+    let data_start = directory.cd_start.checked_sub(shift).ok_or_else(corrupt)?;
+
+    // 拼接后中央目录之后的数据向前移动了附件数据和标识的长度
+    let record: [u8; EOCD_LEN as usize] = read_array(reader, directory.eocd)?;
+    let mut patches = vec![];
+    for (pos, width) in directory.offset_fields(&record) {
+        let offset = match width {
+            4 => u32::from_le_bytes(read_array(reader, pos)?) as u64,
+            _ => u64::from_le_bytes(read_array(reader, pos)?),
+        };
+        let offset = offset.checked_sub(shift).ok_or_else(corrupt)?;
+        patches.push((pos - shift, offset.to_le_bytes()[..width].to_vec()));
+    }
+    let mut carrier = vec![
+        (0, data_start),
+        (directory.cd_start, len - directory.cd_start),
+    ];
+    carrier.retain(|(_, len)| *len > 0);
+    Ok(Some((carrier, vec![(data_start, data_len)], patches)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        layout::{
+            tests::{inspect, round_trip},
+            Layout,
+        },
+        EmbedOptions,
+    };
+
+    const LOCAL_MAGIC: &[u8] = b"PK\x03\x04";
+
+    /// # 生成不压缩的ZIP
+    ///
+    /// 参数:
+    /// * `prefix`: 文件开头的其他数据，如自解压程序，记录中的偏移包括这部分
+    /// * `zip64`: 使用ZIP64目录结束记录
+    /// * `comment`: 目录结束记录之后的注释
+    fn build(files: &[(&str, &[u8])], prefix: &[u8], zip64: bool, comment: &[u8]) -> Vec<u8> {
+        let mut zip = prefix.to_vec();
+        let mut central = vec![];
+        for (name, data) in files {
+            let offset = zip.len() as u32;
+            let mut fields = vec![];
+            fields.extend_from_slice(&crc32fast::hash(data).to_le_bytes());
+            fields.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            fields.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            fields.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            // 版本、标志、压缩方式、时间、日期
+            let head = [20, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+            zip.extend_from_slice(LOCAL_MAGIC);
+            zip.extend_from_slice(&head);
+            zip.extend_from_slice(&fields);
+            zip.extend_from_slice(&[0, 0]);
+            zip.extend_from_slice(name.as_bytes());
+            zip.extend_from_slice(data);
+
+            central.extend_from_slice(CENTRAL_MAGIC);
+            central.extend_from_slice(&[20, 0]);
+            central.extend_from_slice(&head);
+            central.extend_from_slice(&fields);
+            // 扩展字段、注释长度，分卷号，内部和外部属性
+            central.extend_from_slice(&[0; 12]);
+            central.extend_from_slice(&offset.to_le_bytes());
+            central.extend_from_slice(name.as_bytes());
+        }
+        let cd_offset = zip.len() as u64;
+        let count = files.len() as u64;
+        zip.extend_from_slice(&central);
+        if zip64 {
+            let zip64_eocd = zip.len() as u64;
+            zip.extend_from_slice(ZIP64_EOCD_MAGIC);
+            zip.extend_from_slice(&(ZIP64_EOCD_LEN - 12).to_le_bytes());
+            zip.extend_from_slice(&[45, 0, 45, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+            for value in [count, count, central.len() as u64, cd_offset] {
+                zip.extend_from_slice(&value.to_le_bytes());
+            }
+            zip.extend_from_slice(ZIP64_LOCATOR_MAGIC);
+            zip.extend_from_slice(&0u32.to_le_bytes());
+            zip.extend_from_slice(&zip64_eocd.to_le_bytes());
+            zip.extend_from_slice(&1u32.to_le_bytes());
+        }
+        zip.extend_from_slice(EOCD_MAGIC);
+        zip.extend_from_slice(&[0; 4]);
+        if zip64 {
+            zip.extend_from_slice(&[0xff; 12]);
+        } else {
+            zip.extend_from_slice(&(count as u16).to_le_bytes());
+            zip.extend_from_slice(&(count as u16).to_le_bytes());
+            zip.extend_from_slice(&(central.len() as u32).to_le_bytes());
+            zip.extend_from_slice(&(cd_offset as u32).to_le_bytes());
+        }
+        zip.extend_from_slice(&(comment.len() as u16).to_le_bytes());
+        zip.extend_from_slice(comment);
+        zip
+    }
+
+    /// 像解压程序一样按照记录中的偏移读取全部文件，返回文件名和内容
+    fn unzip(zip: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let eocd = zip.windows(4).rposition(|w| w == EOCD_MAGIC).unwrap();
+        let (count, mut pos) = if le_u32(zip, eocd + 16) == u32::MAX {
+            let locator = eocd - ZIP64_LOCATOR_LEN as usize;
+            assert!(zip[locator..].starts_with(ZIP64_LOCATOR_MAGIC));
+            let zip64_eocd = le_u64(zip, locator + 8) as usize;
+            assert!(zip[zip64_eocd..].starts_with(ZIP64_EOCD_MAGIC));
+            (
+                le_u64(zip, zip64_eocd + 32),
+                le_u64(zip, zip64_eocd + 48) as usize,
+            )
+        } else {
+            (
+                le_u16(zip, eocd + 10) as u64,
+                le_u32(zip, eocd + 16) as usize,
+            )
+        };
+        let mut files = vec![];
+        for _ in 0..count {
+            assert!(zip[pos..].starts_with(CENTRAL_MAGIC));
+            let name_len = le_u16(zip, pos + 28) as usize;
+            let name = &zip[pos + 46..pos + 46 + name_len];
+            let local = le_u32(zip, pos + 42) as usize;
+            assert!(zip[local..].starts_with(LOCAL_MAGIC));
+            assert_eq!(&zip[local + 30..local + 30 + name_len], name);
+            let size = le_u32(zip, local + 18) as usize;
+            let data = &zip[local + 30 + name_len..][..size];
+            assert_eq!(crc32fast::hash(data), le_u32(zip, local + 14));
+            files.push((String::from_utf8(name.to_vec()).unwrap(), data.to_vec()));
+            pos += 46 + name_len;
+        }
+        files
+    }
+
+    const FILES: &[(&str, &[u8])] = &[
+        ("META-INF/MANIFEST.MF", b"Manifest-Version: 1.0\n"),
+        ("a.txt", b"hello"),
+    ];
+
+    fn check(carrier: &[u8]) {
+        let expected = unzip(carrier);
+        let output = round_trip(carrier, Layout::ZipDirectory);
+        assert_eq!(unzip(&output), expected);
+    }
+
+    #[test]
+    fn zip_round_trip() {
+        check(&build(FILES, b"", false, b""));
+        check(&build(&[], b"", false, b""));
+    }
+
+    #[test]
+    fn zip_prefix_and_comment() {
+        check(&build(
+            FILES,
+            b"#!/bin/sh\nexit 0\n",
+            false,
+            b"archive comment",
+        ));
+    }
+
+    #[test]
+    fn zip64_round_trip() {
+        check(&build(FILES, b"", true, b""));
+        check(&build(FILES, b"prefix", true, b"comment"));
+    }
+
+    #[test]
+    fn zip_rejected() {
+        let options = EmbedOptions {
+            layout: Layout::ZipDirectory,
+            ..Default::default()
+        };
+        // 分卷压缩
+        let mut split = build(FILES, b"", false, b"");
+        let eocd = split.len() - EOCD_LEN as usize;
+        split[eocd + 4] = 1;
+        for carrier in [&b"not a zip"[..], &split] {
+            let res = crate::embed_bytes(carrier, &[("a.txt", b"a")], &options);
+            assert!(matches!(res, Err(Error::InvalidInput(_))));
+        }
+    }
+
+    #[test]
+    fn zip_forged_length() {
+        let options = EmbedOptions {
+            layout: Layout::ZipDirectory,
+            ..Default::default()
+        };
+        let carrier = build(FILES, b"", false, b"");
+        let mut output = crate::embed_bytes(&carrier, &[("a.txt", b"a")], &options).unwrap();
+        let eocd = output.len() - EOCD_LEN as usize;
+        let cd_start = le_u32(&output, eocd + 16) as usize;
+        output[cd_start - MARKER_LEN as usize..][..8].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(matches!(inspect(&output), Err(Error::CorruptTrailer(_))));
+    }
+}